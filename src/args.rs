@@ -0,0 +1,390 @@
+use crate::execution_context::ExecutionContext;
+use crate::step_config::BytesInput;
+use anyhow::{bail, Result};
+use json::{object, JsonValue};
+use serde::Deserialize;
+
+/// Type of a value serialized as the massa-as-sdk `Args` do
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArgType {
+    Bool,
+    U8,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    String,
+    Bytes,
+}
+
+impl ArgType {
+    /// Returns the typed value of a JSON value, floats are narrowed to their own type
+    fn arg(self, value: &serde_json::Value) -> Result<Arg> {
+        let arg = match self {
+            ArgType::Bool => value.as_bool().map(Arg::Bool),
+            ArgType::U8 => value.as_u64().and_then(|v| v.try_into().ok()).map(Arg::U8),
+            ArgType::I32 => value.as_i64().and_then(|v| v.try_into().ok()).map(Arg::I32),
+            ArgType::U32 => value.as_u64().and_then(|v| v.try_into().ok()).map(Arg::U32),
+            ArgType::I64 => value.as_i64().map(Arg::I64),
+            ArgType::U64 => value.as_u64().map(Arg::U64),
+            ArgType::F32 => value.as_f64().map(|v| Arg::F32(v as f32)),
+            ArgType::F64 => value.as_f64().map(Arg::F64),
+            ArgType::String => value.as_str().map(|v| Arg::String(v.to_string())),
+            ArgType::Bytes => serde_json::from_value(value.clone())
+                .ok()
+                .map(|bytes| Arg::Bytes(BytesInput::Raw(bytes))),
+        };
+        match arg {
+            Some(arg) => Ok(arg),
+            None => bail!("{} is not a valid {:?} value", value, self),
+        }
+    }
+}
+
+/// Typed value serialized as the massa-as-sdk `Args` do, given as `{type: u64, value: 1}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub(crate) enum Arg {
+    Bool(bool),
+    U8(u8),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    /// Address serialized as a string, `$name` refers to a variable
+    Address(String),
+    /// Bytes prefixed by their u32 length
+    Bytes(BytesInput),
+    /// Values prefixed by the u32 length of their serialization, as `Args` serializes arrays
+    Array(Vec<Arg>),
+    /// Values of a `Serializable` object, serialized one after the other without prefix
+    Serializable(Vec<Arg>),
+}
+
+impl Arg {
+    fn serialize(&self, exec_context: &ExecutionContext, buffer: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Arg::Bool(value) => buffer.push(u8::from(*value)),
+            Arg::U8(value) => buffer.push(*value),
+            Arg::I32(value) => buffer.extend(value.to_le_bytes()),
+            Arg::U32(value) => buffer.extend(value.to_le_bytes()),
+            Arg::I64(value) => buffer.extend(value.to_le_bytes()),
+            Arg::U64(value) => buffer.extend(value.to_le_bytes()),
+            Arg::F32(value) => buffer.extend(value.to_le_bytes()),
+            Arg::F64(value) => buffer.extend(value.to_le_bytes()),
+            Arg::String(value) => write_with_len(buffer, value.as_bytes())?,
+            Arg::Address(address) => {
+                write_with_len(buffer, exec_context.resolve(address)?.as_bytes())?
+            }
+            Arg::Bytes(bytes) => write_with_len(buffer, &bytes.to_bytes(exec_context)?)?,
+            Arg::Array(values) => write_with_len(buffer, &serialize_args(values, exec_context)?)?,
+            Arg::Serializable(values) => {
+                for value in values {
+                    value.serialize(exec_context, buffer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_with_len(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = match u32::try_from(bytes.len()) {
+        Ok(len) => len,
+        Err(_) => bail!("{} bytes cannot be prefixed by a u32 length", bytes.len()),
+    };
+    buffer.extend(len.to_le_bytes());
+    buffer.extend(bytes);
+    Ok(())
+}
+
+/// Serializes values one after the other, as `new Args().add(...).add(...).serialize()` does
+pub(crate) fn serialize_args(args: &[Arg], exec_context: &ExecutionContext) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for arg in args {
+        arg.serialize(exec_context, &mut buffer)?;
+    }
+    Ok(buffer)
+}
+
+/// Declared type of the value returned by a function
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ReturnType {
+    /// Value serialized alone, strings and bytes have no length prefix
+    Single(ArgType),
+    /// Values serialized one after the other by `Args`
+    Tuple(Vec<ArgType>),
+}
+
+impl ReturnType {
+    /// Decodes a return value, all of its bytes must be read
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<JsonValue> {
+        let mut reader = ArgsReader { bytes };
+        let value = match self {
+            ReturnType::Single(ArgType::String) => return Ok(std::str::from_utf8(bytes)?.into()),
+            ReturnType::Single(ArgType::Bytes) => return Ok(bytes.into()),
+            ReturnType::Single(arg_type) => reader.read(*arg_type)?,
+            ReturnType::Tuple(arg_types) => {
+                let mut values = JsonValue::new_array();
+                for arg_type in arg_types {
+                    values.push(reader.read(*arg_type)?)?;
+                }
+                values
+            }
+        };
+        if !reader.bytes.is_empty() {
+            bail!(
+                "{} bytes left after decoding the return value",
+                reader.bytes.len()
+            )
+        }
+        Ok(value)
+    }
+}
+
+impl ReturnType {
+    /// Serializes a value as a function returning this type does, so that values are compared
+    /// in their own type
+    pub(crate) fn encode(
+        &self,
+        value: &serde_json::Value,
+        exec_context: &ExecutionContext,
+    ) -> Result<Vec<u8>> {
+        match self {
+            ReturnType::Single(ArgType::String) => match value.as_str() {
+                Some(value) => Ok(value.as_bytes().to_vec()),
+                None => bail!("{} is not a valid String value", value),
+            },
+            ReturnType::Single(ArgType::Bytes) => Ok(serde_json::from_value(value.clone())?),
+            ReturnType::Single(arg_type) => serialize_args(&[arg_type.arg(value)?], exec_context),
+            ReturnType::Tuple(arg_types) => {
+                let values = match value.as_array() {
+                    Some(values) if values.len() == arg_types.len() => values,
+                    _ => bail!("{} is not a list of {} values", value, arg_types.len()),
+                };
+                let args = arg_types
+                    .iter()
+                    .zip(values)
+                    .map(|(arg_type, value)| arg_type.arg(value))
+                    .collect::<Result<Vec<_>>>()?;
+                serialize_args(&args, exec_context)
+            }
+        }
+    }
+}
+
+/// Reader of values serialized by the massa-as-sdk `Args`, numbers are little endian and
+/// strings and bytes are prefixed by their u32 length
+struct ArgsReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ArgsReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            bail!(
+                "cannot read {} bytes, only {} are left",
+                len,
+                self.bytes.len()
+            )
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+    fn read_len(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?) as usize)
+    }
+    fn read(&mut self, arg_type: ArgType) -> Result<JsonValue> {
+        let value = match arg_type {
+            ArgType::Bool => (self.take(1)?[0] != 0).into(),
+            ArgType::U8 => self.take(1)?[0].into(),
+            ArgType::I32 => i32::from_le_bytes(self.take(4)?.try_into()?).into(),
+            ArgType::U32 => u32::from_le_bytes(self.take(4)?.try_into()?).into(),
+            ArgType::I64 => i64::from_le_bytes(self.take(8)?.try_into()?).into(),
+            ArgType::U64 => u64::from_le_bytes(self.take(8)?.try_into()?).into(),
+            ArgType::F32 => f32::from_le_bytes(self.take(4)?.try_into()?).into(),
+            ArgType::F64 => f64::from_le_bytes(self.take(8)?.try_into()?).into(),
+            ArgType::String => {
+                let len = self.read_len()?;
+                String::from_utf8(self.take(len)?.to_vec())?.into()
+            }
+            ArgType::Bytes => {
+                let len = self.read_len()?;
+                self.take(len)?.into()
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// Returns the trace of a return value, decoded if its type is given
+pub(crate) fn return_value_to_json(ret: &[u8], return_type: Option<&ReturnType>) -> JsonValue {
+    let mut json = object!(
+        raw: ret,
+        hex: hex::encode(ret),
+    );
+    if let Some(return_type) = return_type {
+        match return_type.decode(ret) {
+            Ok(decoded) => json["decoded"] = decoded,
+            Err(err) => json["decode_error"] = err.to_string().into(),
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_return_values() {
+        let cases: Vec<(ReturnType, Vec<u8>, Result<JsonValue, &str>)> = vec![
+            (ReturnType::Single(ArgType::U8), vec![7], Ok(7.into())),
+            (
+                ReturnType::Single(ArgType::U64),
+                vec![1, 0, 0, 0, 0, 0, 0, 0],
+                Ok(1.into()),
+            ),
+            (
+                ReturnType::Single(ArgType::String),
+                b"massa".to_vec(),
+                Ok("massa".into()),
+            ),
+            (
+                ReturnType::Tuple(vec![ArgType::String, ArgType::Bool]),
+                vec![2, 0, 0, 0, b'o', b'k', 1],
+                Ok(json::array!["ok", true]),
+            ),
+            // truncated value
+            (
+                ReturnType::Single(ArgType::U32),
+                vec![1, 0, 0],
+                Err("cannot read 4 bytes, only 3 are left"),
+            ),
+            // trailing bytes
+            (
+                ReturnType::Single(ArgType::I32),
+                vec![1, 0, 0, 0, 9],
+                Err("1 bytes left after decoding the return value"),
+            ),
+            // length prefix longer than the data
+            (
+                ReturnType::Tuple(vec![ArgType::Bytes]),
+                vec![5, 0, 0, 0, 1, 2],
+                Err("cannot read 5 bytes, only 2 are left"),
+            ),
+            // invalid UTF-8
+            (
+                ReturnType::Single(ArgType::String),
+                vec![0xff, 0xfe],
+                Err("invalid utf-8 sequence of 1 bytes from index 0"),
+            ),
+            (
+                ReturnType::Tuple(vec![ArgType::String]),
+                vec![1, 0, 0, 0, 0xff],
+                Err("invalid utf-8 sequence of 1 bytes from index 0"),
+            ),
+        ];
+        for (return_type, bytes, expected) in cases {
+            let decoded = return_type.decode(&bytes).map_err(|err| err.to_string());
+            assert_eq!(
+                decoded,
+                expected.map_err(String::from),
+                "{:?} of {:?}",
+                return_type,
+                bytes
+            );
+        }
+    }
+
+    fn exec_context() -> ExecutionContext {
+        let gas_costs = massa_sc_runtime::GasCosts::new(
+            crate::constants::ABI_GAS_COSTS_PATH.into(),
+            crate::constants::WASM_GAS_COSTS_PATH.into(),
+        )
+        .unwrap();
+        ExecutionContext::from_ledger(0, gas_costs, Default::default()).unwrap()
+    }
+
+    /// Byte vectors produced by `new Args().add(..).serialize()` in massa-as-sdk
+    #[test]
+    fn serialize_args_like_massa_as_sdk() {
+        let exec_context = exec_context();
+        exec_context.set_variable("token", "AS12").unwrap();
+        let cases: Vec<(&str, Vec<u8>)> = vec![
+            // add("hello")
+            (
+                "[{type: string, value: hello}]",
+                vec![5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o'],
+            ),
+            // add(new Address("AS12"))
+            (
+                "[{type: address, value: $token}]",
+                vec![4, 0, 0, 0, b'A', b'S', b'1', b'2'],
+            ),
+            // add<StaticArray<u8>>([1, 2, 3])
+            (
+                "[{type: bytes, value: {hex: \"010203\"}}]",
+                vec![3, 0, 0, 0, 1, 2, 3],
+            ),
+            // add(true).add<u8>(7).add<i32>(-1).add<u64>(258)
+            (
+                "[{type: bool, value: true}, {type: u8, value: 7}, {type: i32, value: -1}, \
+                 {type: u64, value: 258}]",
+                vec![1, 7, 255, 255, 255, 255, 2, 1, 0, 0, 0, 0, 0, 0],
+            ),
+            // add<f32>(1.5)
+            ("[{type: f32, value: 1.5}]", vec![0, 0, 0xc0, 0x3f]),
+            // add<Array<u32>>([1, 2])
+            (
+                "[{type: array, value: [{type: u32, value: 1}, {type: u32, value: 2}]}]",
+                vec![8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0],
+            ),
+            // add<Array<string>>(["a", "bc"])
+            (
+                "[{type: array, value: [{type: string, value: a}, {type: string, value: bc}]}]",
+                vec![11, 0, 0, 0, 1, 0, 0, 0, b'a', 2, 0, 0, 0, b'b', b'c'],
+            ),
+            // add(new Pair("a", 1)) with a serialize() of new Args().add(a).add<u8>(b)
+            (
+                "[{type: serializable, value: [{type: string, value: a}, {type: u8, value: 1}]}]",
+                vec![1, 0, 0, 0, b'a', 1],
+            ),
+            // add<Array<Pair>>([new Pair("a", 1)])
+            (
+                "[{type: array, value: [{type: serializable, value: \
+                 [{type: string, value: a}, {type: u8, value: 1}]}]}]",
+                vec![6, 0, 0, 0, 1, 0, 0, 0, b'a', 1],
+            ),
+        ];
+        for (yaml, expected) in cases {
+            let input: BytesInput = serde_yaml::from_str(yaml).unwrap();
+            assert_eq!(input.to_bytes(&exec_context).unwrap(), expected, "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn invalid_typed_values_are_reported() {
+        let cases = [
+            ("[{type: u65, value: 1}]", "unknown variant `u65`"),
+            ("[{type: u8, value: 256}]", "invalid value: integer `256`"),
+            (
+                "[1, {type: u8, value: 1}]",
+                "cannot mix bytes and typed values",
+            ),
+            ("{hexa: \"01\"}", "unknown variant `hexa`"),
+        ];
+        for (yaml, expected) in cases {
+            let err = serde_yaml::from_str::<BytesInput>(yaml).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}: {}", yaml, err);
+        }
+    }
+}
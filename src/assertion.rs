@@ -0,0 +1,158 @@
+use crate::args::ReturnType;
+use crate::execution_context::{ExecutionContext, Slot};
+use anyhow::{bail, Result};
+
+pub(crate) fn check_balance(
+    exec_context: &ExecutionContext,
+    address: &str,
+    expected: u64,
+) -> Result<()> {
+    let balance = exec_context.get_entry(address)?.balance;
+    if balance != expected {
+        bail!(
+            "balance of {} is {}, expected {}",
+            address,
+            balance,
+            expected
+        )
+    }
+    Ok(())
+}
+
+pub(crate) fn check_datastore_entry(
+    exec_context: &ExecutionContext,
+    address: &str,
+    key: &[u8],
+    expected: Option<&[u8]>,
+) -> Result<()> {
+    let entry = exec_context.get_entry(address)?;
+    if !entry.has_data(key) {
+        bail!("datastore of {} has no entry for key {:?}", address, key)
+    }
+    if let Some(expected) = expected {
+        let value = entry.get_data(key);
+        if value != expected {
+            bail!(
+                "datastore entry {:?} of {} is {:?}, expected {:?}",
+                key,
+                address,
+                value,
+                expected
+            )
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_event_emitted(
+    exec_context: &ExecutionContext,
+    start: Option<Slot>,
+    end: Option<Slot>,
+    sender_address: Option<&str>,
+    data: &str,
+) -> Result<()> {
+    let emitted = exec_context
+        .get_events_in(start, end)?
+        .iter()
+        .filter(|event| {
+            sender_address.is_none() || sender_address == Some(event.sender_address.as_str())
+        })
+        .any(|event| event.data == data);
+    if !emitted {
+        match sender_address {
+            Some(sender_address) => bail!(
+                "no event {:?} emitted by {} in the given slot range",
+                data,
+                sender_address
+            ),
+            None => bail!("no event {:?} emitted in the given slot range", data),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_async_message_pending(
+    exec_context: &ExecutionContext,
+    start: Option<Slot>,
+    end: Option<Slot>,
+    sender_address: Option<&str>,
+    target_address: Option<&str>,
+    target_handler: Option<&str>,
+) -> Result<()> {
+    let pending = exec_context
+        .get_async_messages_in(start, end)?
+        .iter()
+        .any(|message| {
+            (sender_address.is_none() || sender_address == Some(message.sender_address.as_str()))
+                && (target_address.is_none()
+                    || target_address == Some(message.target_address.as_str()))
+                && (target_handler.is_none()
+                    || target_handler == Some(message.target_handler.as_str()))
+        });
+    if !pending {
+        bail!("no matching async message pending in the given slot range")
+    }
+    Ok(())
+}
+
+pub(crate) fn check_remaining_gas_between(
+    exec_context: &ExecutionContext,
+    min: u64,
+    max: u64,
+) -> Result<()> {
+    let remaining_gas = match exec_context.last_remaining_gas {
+        Some(remaining_gas) => remaining_gas,
+        None => bail!(
+            "the last execute_sc or call_sc step failed or none was run before this assertion"
+        ),
+    };
+    if !(min..=max).contains(&remaining_gas) {
+        bail!(
+            "remaining gas is {}, expected between {} and {}",
+            remaining_gas,
+            min,
+            max
+        )
+    }
+    Ok(())
+}
+
+pub(crate) fn check_return_value(
+    exec_context: &ExecutionContext,
+    raw: Option<&[u8]>,
+    return_type: Option<&ReturnType>,
+    value: Option<&serde_json::Value>,
+) -> Result<()> {
+    if raw.is_none() && value.is_none() {
+        bail!("raw or value is required")
+    }
+    let return_value = match &exec_context.last_return_value {
+        Some(return_value) => return_value,
+        None => bail!("no step returned a value before this assertion"),
+    };
+    if let Some(raw) = raw {
+        if return_value.as_slice() != raw {
+            bail!(
+                "return value is {}, expected {}",
+                hex::encode(return_value),
+                hex::encode(raw)
+            )
+        }
+    }
+    if let Some(value) = value {
+        let return_type = match return_type {
+            Some(return_type) => return_type,
+            None => bail!("a return_type is needed to compare the decoded return value"),
+        };
+        // the expected value is serialized rather than the return value decoded, so that
+        // floats are compared in their own type
+        if &return_type.encode(value, exec_context)? != return_value {
+            let decoded = match return_type.decode(return_value) {
+                Ok(decoded) => decoded.dump(),
+                Err(_) => hex::encode(return_value),
+            };
+            bail!("decoded return value is {}, expected {}", decoded, value)
+        }
+    }
+    Ok(())
+}
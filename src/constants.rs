@@ -1,4 +1,11 @@
 pub const TRACE_PATH: &str = "./trace.json";
 pub const LEDGER_PATH: &str = "./ledger.json";
+pub const STATE_PATH: &str = "./state.json";
 pub const ABI_GAS_COSTS_PATH: &str = "./gas_costs/abi_gas_costs.json";
 pub const WASM_GAS_COSTS_PATH: &str = "./gas_costs/wasm_gas_costs.json";
+pub const MAX_ASYNC_GAS: u64 = 1_000_000_000;
+pub const THREAD_COUNT: u8 = 32;
+/// Version of the state file format, to bump on every incompatible change
+pub const STATE_VERSION: u32 = 1;
+/// massa-sc-runtime tag of Cargo.toml, to update with it as it invalidates the cached modules
+pub const RUNTIME_VERSION: &str = "v0.10.0";
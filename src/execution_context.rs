@@ -1,31 +1,78 @@
 use anyhow::{bail, Result};
 use base64::{engine::general_purpose, Engine as _};
 use json::{object, JsonValue};
-use massa_sc_runtime::GasCosts;
+use massa_hash::Hash;
+use massa_models::address::Address;
+use massa_sc_runtime::{GasCosts, RuntimeModule};
+use massa_signature::KeyPair;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::{
-    cmp::Ordering,
-    collections::BTreeMap,
-    ops::Bound,
-    path::Path,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
-use crate::constants::{ABI_GAS_COSTS_PATH, LEDGER_PATH, WASM_GAS_COSTS_PATH};
+use crate::constants::{MAX_ASYNC_GAS, STATE_VERSION, THREAD_COUNT};
+use crate::module_cache::{CompiledModules, ModuleCache};
 
-#[derive(Clone, Default, Deserialize, Serialize)]
-pub(crate) struct Entry {
+/// Serializes datastore keys as base64 strings, raw byte keys cannot be JSON object keys
+mod base64_keys {
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub(crate) fn serialize<S: Serializer>(
+        datastore: &BTreeMap<Vec<u8>, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        datastore
+            .iter()
+            .map(|(key, value)| (general_purpose::STANDARD.encode(key), value))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, D::Error> {
+        BTreeMap::<String, Vec<u8>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| {
+                general_purpose::STANDARD
+                    .decode(key)
+                    .map(|key| (key, value))
+                    .map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
+
+/// Ledger entry of an address
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Entry {
+    /// Balance in raw coins, 1 raw_coin = 1e-9 coin
     pub balance: u64,
+    /// Bytecode of the smart contract, empty for user addresses
     pub bytecode: Vec<u8>,
-    pub datastore: BTreeMap<String, Vec<u8>>,
+    /// Datastore of the address
+    #[serde(with = "base64_keys")]
+    pub datastore: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
 impl Into<JsonValue> for Entry {
     fn into(self) -> JsonValue {
+        let datastore: BTreeMap<String, Vec<u8>> = self
+            .datastore
+            .into_iter()
+            .map(|(key, value)| (general_purpose::STANDARD.encode(key), value))
+            .collect();
         object!(
             balance: self.balance,
             bytecode: self.bytecode,
-            datastore: self.datastore,
+            datastore: datastore,
         )
     }
 }
@@ -35,18 +82,30 @@ impl Entry {
         self.bytecode.clone()
     }
     pub(crate) fn get_data(&self, key: &[u8]) -> Vec<u8> {
-        self.datastore
-            .get(&general_purpose::STANDARD.encode(key))
-            .cloned()
-            .unwrap_or_default()
+        self.datastore.get(key).cloned().unwrap_or_default()
     }
     pub(crate) fn has_data(&self, key: &[u8]) -> bool {
+        self.datastore.contains_key(key)
+    }
+    /// Returns the datastore keys starting with the given prefix in lexicographic byte order
+    pub(crate) fn get_keys(&self, prefix: Option<&[u8]>) -> BTreeSet<Vec<u8>> {
+        let prefix = prefix.unwrap_or_default();
         self.datastore
-            .contains_key(&general_purpose::STANDARD.encode(key))
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect()
     }
     pub(crate) fn insert_data(&mut self, key: &[u8], value: &[u8]) {
+        self.datastore.insert(key.to_vec(), value.to_vec());
+    }
+    pub(crate) fn delete_data(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.datastore.remove(key)
+    }
+    pub(crate) fn append_data(&mut self, key: &[u8], value: &[u8]) -> Option<()> {
         self.datastore
-            .insert(general_purpose::STANDARD.encode(key), value.to_vec());
+            .get_mut(key)
+            .map(|data| data.extend_from_slice(value))
     }
 }
 
@@ -81,6 +140,39 @@ impl Ledger {
                 entry
             });
     }
+    pub(crate) fn delete_data_entry(&mut self, address: &str, key: &[u8]) -> Result<()> {
+        let entry = match self.0.get_mut(address) {
+            Some(entry) => entry,
+            None => bail!("cannot find {} in the ledger", address),
+        };
+        if entry.delete_data(key).is_none() {
+            bail!(
+                "cannot delete key {:?} of {}, it does not exist",
+                key,
+                address
+            )
+        }
+        Ok(())
+    }
+    pub(crate) fn append_data_entry(
+        &mut self,
+        address: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        let entry = match self.0.get_mut(address) {
+            Some(entry) => entry,
+            None => bail!("cannot find {} in the ledger", address),
+        };
+        if entry.append_data(key, value).is_none() {
+            bail!(
+                "cannot append to key {:?} of {}, it does not exist",
+                key,
+                address
+            )
+        }
+        Ok(())
+    }
     pub(crate) fn sub(&mut self, address: &str, amount: u64) -> Result<()> {
         let entry = match self.0.get_mut(address) {
             Some(entry) => entry,
@@ -123,12 +215,30 @@ pub(crate) struct CallItem {
     pub coins: u64,
 }
 
+/// Slot of the simulated chain
 #[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Slot {
     pub period: u64,
     pub thread: u8,
 }
 
+impl Slot {
+    /// Returns the slot following this one
+    pub fn next(&self) -> Slot {
+        if self.thread + 1 < THREAD_COUNT {
+            Slot {
+                period: self.period,
+                thread: self.thread + 1,
+            }
+        } else {
+            Slot {
+                period: self.period + 1,
+                thread: 0,
+            }
+        }
+    }
+}
+
 impl PartialOrd for Slot {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         (self.period, self.thread).partial_cmp(&(other.period, other.thread))
@@ -141,87 +251,351 @@ impl Ord for Slot {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
-pub(crate) struct AsyncMessage {
+impl Into<JsonValue> for Slot {
+    fn into(self) -> JsonValue {
+        object!(
+            period: self.period,
+            thread: self.thread,
+        )
+    }
+}
+
+/// Ledger change that makes a filtered message executable
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AsyncMessageTrigger {
+    /// Address whose modification triggers the message
+    pub address: String,
+    /// Datastore key of the address whose modification triggers the message,
+    /// any modification of the address does if omitted
+    pub datastore_key: Option<Vec<u8>>,
+}
+
+impl Into<JsonValue> for AsyncMessageTrigger {
+    fn into(self) -> JsonValue {
+        object!(
+            address: self.address,
+            datastore_key: self.datastore_key,
+        )
+    }
+}
+
+/// Asynchronous message waiting in the pool
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AsyncMessage {
+    /// Slot at which the message was emitted, set by the pool
+    pub emission_slot: Slot,
+    /// Index of the message among the ones emitted during its slot, set by the pool
+    pub emission_index: u64,
     pub sender_address: String,
     pub target_address: String,
     pub target_handler: String,
+    /// First slot at which the message can be executed
+    pub validity_start: Slot,
+    /// Slot at which the message expires, excluded from its validity window
+    pub validity_end: Slot,
     pub gas: u64,
+    pub gas_price: u64,
     pub coins: u64,
     pub data: Vec<u8>,
+    /// Ledger change the message waits for before being executable
+    pub trigger: Option<AsyncMessageTrigger>,
+    /// Whether the trigger of the message has fired, always true for unfiltered messages
+    pub can_be_executed: bool,
 }
 
 impl Into<JsonValue> for AsyncMessage {
     fn into(self) -> JsonValue {
         object!(
+            emission_slot: self.emission_slot,
+            emission_index: self.emission_index,
             sender_address: self.sender_address,
             target_address: self.target_address,
             target_handler: self.target_handler,
+            validity_start: self.validity_start,
+            validity_end: self.validity_end,
             gas: self.gas,
+            gas_price: self.gas_price,
             coins: self.coins,
             data: self.data,
+            trigger: self.trigger,
+            can_be_executed: self.can_be_executed,
+        )
+    }
+}
+
+/// Messages are ordered by decreasing gas price, then by emission
+type AsyncMessageId = (Reverse<u64>, Slot, u64);
+
+impl AsyncMessage {
+    fn id(&self) -> AsyncMessageId {
+        (
+            Reverse(self.gas_price),
+            self.emission_slot,
+            self.emission_index,
         )
     }
 }
 
-type AsyncPool = BTreeMap<Slot, Vec<AsyncMessage>>;
+type AsyncPool = BTreeMap<AsyncMessageId, AsyncMessage>;
+
+/// Addresses modified since the last async pool update with their modified datastore keys
+type LedgerChanges = BTreeMap<String, BTreeSet<Vec<u8>>>;
+
+/// Formats an address as massa_models does: `A`, then `U` for users or `S` for smart
+/// contracts, then the bs58check of the version varint followed by the hash
+pub(crate) fn format_address(prefix: char, version: u64, hash: &[u8]) -> String {
+    let mut data = Vec::new();
+    let mut remaining = version;
+    loop {
+        let byte = (remaining & 0x7f) as u8;
+        remaining >>= 7;
+        if remaining == 0 {
+            data.push(byte);
+            break;
+        }
+        data.push(byte | 0x80);
+    }
+    data.extend(hash);
+    format!(
+        "A{}{}",
+        prefix,
+        bs58::encode(data).with_check().into_string()
+    )
+}
+
+/// Marks the filtered messages whose trigger matches one of the given changes as executable
+fn fire_triggers(async_pool: &mut AsyncPool, ledger_changes: &LedgerChanges) {
+    for message in async_pool.values_mut() {
+        if let Some(trigger) = &message.trigger {
+            if let Some(changed_keys) = ledger_changes.get(&trigger.address) {
+                match &trigger.datastore_key {
+                    Some(key) if !changed_keys.contains(key) => (),
+                    _ => message.can_be_executed = true,
+                }
+            }
+        }
+    }
+}
 
-#[derive(Clone, Debug, Serialize)]
-pub(crate) struct Event {
-    sender_address: String,
-    data: String,
+/// Event emitted by a smart contract or reporting a failed async message
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+    pub sender_address: String,
+    pub data: String,
+    /// Whether the event reports an execution error
+    pub is_error: bool,
 }
 
 impl Into<JsonValue> for Event {
     fn into(self) -> JsonValue {
         object!(
             sender_address: self.sender_address,
-            data: self.data
+            data: self.data,
+            is_error: self.is_error
         )
     }
 }
 
 type EventPool = BTreeMap<Slot, Vec<Event>>;
 
+pub(crate) type OperationDatastore = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Counters reset at the beginning of each slot
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+struct SlotCounters {
+    /// Number of addresses created during the slot
+    created_addr_index: u64,
+    /// Number of asynchronous messages emitted during the slot
+    emitted_msg_index: u64,
+    /// Gas allotted to the asynchronous messages executed during the slot
+    async_gas_used: u64,
+}
+
+/// Files read and written by the execution context
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ContextPaths {
+    /// Ledger loaded at start, the ledger starts empty if None
+    pub ledger_input: Option<PathBuf>,
+    /// Ledger written on save, not written if None
+    pub ledger_output: Option<PathBuf>,
+    /// Chain state loaded at start, the state starts empty if None
+    pub state_input: Option<PathBuf>,
+    /// Chain state written on save, not written if None
+    pub state_output: Option<PathBuf>,
+    pub abi_gas_costs: PathBuf,
+    pub wasm_gas_costs: PathBuf,
+    /// Directory the compiled modules are saved to, they are only kept in memory if None
+    pub module_cache: Option<PathBuf>,
+}
+
+/// Chain state persisted next to the ledger between runs
+#[derive(Default, Deserialize, Serialize)]
+struct ChainState {
+    version: u32,
+    execution_slot: Slot,
+    slot_counters: SlotCounters,
+    /// Pending messages, the pool keys are rebuilt from them on load
+    async_pool: Vec<AsyncMessage>,
+    /// Events by slot, slots cannot be JSON object keys
+    event_pool: Vec<(Slot, Vec<Event>)>,
+    ledger_changes: LedgerChanges,
+}
+
+impl ChainState {
+    /// Loads the state file
+    fn load(path: &Path) -> Result<ChainState> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => bail!("cannot open state file {}: {}", path.display(), err),
+        };
+        let reader = std::io::BufReader::new(file);
+        let content: serde_json::Value = serde_json::from_reader(reader)?;
+        match content.get("version").and_then(|version| version.as_u64()) {
+            Some(version) if version == STATE_VERSION as u64 => {
+                Ok(serde_json::from_value(content)?)
+            }
+            Some(version) => bail!(
+                "state file {} has version {}, expected {}, remove it to start from an empty state",
+                path.display(),
+                version,
+                STATE_VERSION
+            ),
+            None => bail!(
+                "state file {} has no version, remove it to start from an empty state",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Copy of the state that a smart contract execution can modify
+#[derive(Clone)]
+pub(crate) struct ContextSnapshot {
+    ledger: Ledger,
+    async_pool: AsyncPool,
+    event_pool: EventPool,
+    slot_counters: SlotCounters,
+    ledger_changes: LedgerChanges,
+}
+
+/// Named copy of the whole simulated state, taken by a snapshot_state step
+#[derive(Clone)]
+struct StateSnapshot {
+    context: ContextSnapshot,
+    owned: VecDeque<String>,
+    variables: BTreeMap<String, String>,
+}
+
 #[derive(Clone)]
 pub(crate) struct ExecutionContext {
     pub gas_costs: GasCosts,
     ledger: Arc<Mutex<Ledger>>,
-    call_stack: Arc<Mutex<std::collections::VecDeque<CallItem>>>,
-    owned: Arc<Mutex<std::collections::VecDeque<String>>>,
+    call_stack: Arc<Mutex<VecDeque<CallItem>>>,
+    owned: Arc<Mutex<VecDeque<String>>>,
     async_pool: Arc<Mutex<AsyncPool>>,
     event_pool: Arc<Mutex<EventPool>>,
+    op_datastore: Arc<Mutex<Option<OperationDatastore>>>,
+    slot_counters: Arc<Mutex<SlotCounters>>,
+    ledger_changes: Arc<Mutex<LedgerChanges>>,
+    variables: Arc<Mutex<BTreeMap<String, String>>>,
+    rng: Arc<Mutex<StdRng>>,
+    keypairs: Arc<Mutex<BTreeMap<String, KeyPair>>>,
+    state_snapshots: Arc<Mutex<BTreeMap<String, StateSnapshot>>>,
+    module_cache: Arc<Mutex<ModuleCache>>,
     execution_trace: Arc<Mutex<JsonValue>>,
     pub execution_slot: Slot,
+    pub last_remaining_gas: Option<u64>,
+    pub last_return_value: Option<Vec<u8>>,
+    paths: ContextPaths,
 }
 
 impl ExecutionContext {
-    pub(crate) fn new() -> Result<ExecutionContext> {
-        Ok(ExecutionContext {
-            gas_costs: GasCosts::new(
-                Path::new(ABI_GAS_COSTS_PATH).to_path_buf(),
-                Path::new(WASM_GAS_COSTS_PATH).to_path_buf(),
-            )?,
-            ledger: if let Ok(file) = std::fs::File::open(LEDGER_PATH) {
-                let reader = std::io::BufReader::new(file);
-                let content: BTreeMap<String, Entry> = serde_json::from_reader(reader)?;
-                Arc::new(Mutex::new(Ledger(content)))
-            } else {
-                Default::default()
+    /// Creates a context from the given files, sharing the compiled modules of the run
+    pub(crate) fn new(
+        seed: u64,
+        paths: ContextPaths,
+        modules: CompiledModules,
+    ) -> Result<ExecutionContext> {
+        let gas_costs = GasCosts::new(paths.abi_gas_costs.clone(), paths.wasm_gas_costs.clone())?;
+        let ledger = match &paths.ledger_input {
+            Some(path) => match std::fs::File::open(path) {
+                Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+                Err(err) => bail!("cannot open ledger file {}: {}", path.display(), err),
             },
+            None => Default::default(),
+        };
+        let state = match &paths.state_input {
+            Some(path) => ChainState::load(path)?,
+            None => Default::default(),
+        };
+        let module_cache = ModuleCache::new(&paths, modules)?;
+        ExecutionContext::with_state(seed, gas_costs, ledger, state, paths, module_cache)
+    }
+    /// Creates a context from the given ledger with an empty chain state, nothing is saved
+    pub(crate) fn from_ledger(
+        seed: u64,
+        gas_costs: GasCosts,
+        ledger: BTreeMap<String, Entry>,
+    ) -> Result<ExecutionContext> {
+        ExecutionContext::with_state(
+            seed,
+            gas_costs,
+            ledger,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+    fn with_state(
+        seed: u64,
+        gas_costs: GasCosts,
+        ledger: BTreeMap<String, Entry>,
+        state: ChainState,
+        paths: ContextPaths,
+        module_cache: ModuleCache,
+    ) -> Result<ExecutionContext> {
+        Ok(ExecutionContext {
+            gas_costs,
+            ledger: Arc::new(Mutex::new(Ledger(ledger))),
             call_stack: Default::default(),
             owned: Default::default(),
-            async_pool: Default::default(),
-            execution_slot: Default::default(),
-            event_pool: Default::default(),
+            async_pool: Arc::new(Mutex::new(
+                state
+                    .async_pool
+                    .into_iter()
+                    .map(|message| (message.id(), message))
+                    .collect(),
+            )),
+            execution_slot: state.execution_slot,
+            last_remaining_gas: None,
+            last_return_value: None,
+            event_pool: Arc::new(Mutex::new(state.event_pool.into_iter().collect())),
+            op_datastore: Default::default(),
+            slot_counters: Arc::new(Mutex::new(state.slot_counters)),
+            ledger_changes: Arc::new(Mutex::new(state.ledger_changes)),
+            variables: Default::default(),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            keypairs: Default::default(),
+            state_snapshots: Default::default(),
+            module_cache: Arc::new(Mutex::new(module_cache)),
             execution_trace: Arc::new(Mutex::new(JsonValue::new_array())),
+            paths,
         })
     }
     pub(crate) fn create_new_entry(&self, address: String, entry: Entry) -> Result<()> {
+        let mut changed_keys: BTreeSet<Vec<u8>> = entry.datastore.keys().cloned().collect();
         match self.ledger.lock() {
-            Ok(mut ledger) => ledger.0.insert(address, entry),
+            Ok(mut ledger) => {
+                if let Some(previous) = ledger.0.insert(address.clone(), entry) {
+                    changed_keys.extend(previous.datastore.into_keys());
+                }
+            }
             Err(err) => bail!("create_entry lock error: {}", err),
         };
+        self.record_ledger_change(&address, None)?;
+        for key in changed_keys {
+            self.record_ledger_change(&address, Some(&key))?;
+        }
         Ok(())
     }
     pub(crate) fn get_entry(&self, address: &str) -> Result<Entry> {
@@ -230,13 +604,264 @@ impl ExecutionContext {
             Err(err) => bail!("get_entry lock error: {}", err),
         }
     }
+    /// Writes the ledger and the rest of the chain state to their files
     pub(crate) fn save(&self) -> Result<()> {
+        if let Some(path) = &self.paths.ledger_output {
+            match self.ledger.lock() {
+                Ok(ledger) => {
+                    let ser_ledger = serde_json::to_string_pretty(&ledger.0)?;
+                    std::fs::write(path, ser_ledger)?;
+                }
+                Err(err) => bail!("save lock error: {}", err),
+            };
+        }
+        let path = match &self.paths.state_output {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let snapshot = self.take_snapshot()?;
+        let state = ChainState {
+            version: STATE_VERSION,
+            execution_slot: self.execution_slot,
+            slot_counters: snapshot.slot_counters,
+            async_pool: snapshot.async_pool.into_values().collect(),
+            event_pool: snapshot.event_pool.into_iter().collect(),
+            ledger_changes: snapshot.ledger_changes,
+        };
+        let ser_state = serde_json::to_string_pretty(&state)?;
+        Ok(std::fs::write(path, ser_state)?)
+    }
+    /// Returns an independent copy of the context sharing only the module cache, without the
+    /// state snapshots, the copy never writes the ledger and chain state files
+    pub(crate) fn fork(&self) -> Result<ExecutionContext> {
+        /// Copies the value behind a mutex into a new one
+        fn copy<T: Clone>(value: &Arc<Mutex<T>>) -> Result<Arc<Mutex<T>>> {
+            match value.lock() {
+                Ok(value) => Ok(Arc::new(Mutex::new(value.clone()))),
+                Err(err) => bail!("fork lock error: {}", err),
+            }
+        }
+        Ok(ExecutionContext {
+            gas_costs: self.gas_costs.clone(),
+            ledger: copy(&self.ledger)?,
+            call_stack: copy(&self.call_stack)?,
+            owned: copy(&self.owned)?,
+            async_pool: copy(&self.async_pool)?,
+            event_pool: copy(&self.event_pool)?,
+            op_datastore: copy(&self.op_datastore)?,
+            slot_counters: copy(&self.slot_counters)?,
+            ledger_changes: copy(&self.ledger_changes)?,
+            variables: copy(&self.variables)?,
+            rng: copy(&self.rng)?,
+            keypairs: copy(&self.keypairs)?,
+            // a fork never restores the saved states
+            state_snapshots: Default::default(),
+            module_cache: self.module_cache.clone(),
+            execution_trace: Arc::new(Mutex::new(JsonValue::new_array())),
+            execution_slot: self.execution_slot,
+            last_remaining_gas: self.last_remaining_gas,
+            last_return_value: self.last_return_value.clone(),
+            paths: ContextPaths {
+                ledger_output: None,
+                state_output: None,
+                ..self.paths.clone()
+            },
+        })
+    }
+    pub(crate) fn take_snapshot(&self) -> Result<ContextSnapshot> {
+        let ledger = match self.ledger.lock() {
+            Ok(ledger) => ledger.clone(),
+            Err(err) => bail!("take_snapshot lock error: {}", err),
+        };
+        let async_pool = match self.async_pool.lock() {
+            Ok(async_pool) => async_pool.clone(),
+            Err(err) => bail!("take_snapshot lock error: {}", err),
+        };
+        let event_pool = match self.event_pool.lock() {
+            Ok(event_pool) => event_pool.clone(),
+            Err(err) => bail!("take_snapshot lock error: {}", err),
+        };
+        let slot_counters = match self.slot_counters.lock() {
+            Ok(slot_counters) => *slot_counters,
+            Err(err) => bail!("take_snapshot lock error: {}", err),
+        };
+        let ledger_changes = match self.ledger_changes.lock() {
+            Ok(ledger_changes) => ledger_changes.clone(),
+            Err(err) => bail!("take_snapshot lock error: {}", err),
+        };
+        Ok(ContextSnapshot {
+            ledger,
+            async_pool,
+            event_pool,
+            slot_counters,
+            ledger_changes,
+        })
+    }
+    pub(crate) fn restore_snapshot(&self, snapshot: ContextSnapshot) -> Result<()> {
         match self.ledger.lock() {
-            Ok(ledger) => {
-                let ser_ledger = serde_json::to_string_pretty(&ledger.0)?;
-                Ok(std::fs::write(LEDGER_PATH, ser_ledger)?)
+            Ok(mut ledger) => *ledger = snapshot.ledger,
+            Err(err) => bail!("restore_snapshot lock error: {}", err),
+        };
+        match self.async_pool.lock() {
+            Ok(mut async_pool) => *async_pool = snapshot.async_pool,
+            Err(err) => bail!("restore_snapshot lock error: {}", err),
+        };
+        match self.event_pool.lock() {
+            Ok(mut event_pool) => *event_pool = snapshot.event_pool,
+            Err(err) => bail!("restore_snapshot lock error: {}", err),
+        };
+        match self.slot_counters.lock() {
+            Ok(mut slot_counters) => *slot_counters = snapshot.slot_counters,
+            Err(err) => bail!("restore_snapshot lock error: {}", err),
+        };
+        match self.ledger_changes.lock() {
+            Ok(mut ledger_changes) => *ledger_changes = snapshot.ledger_changes,
+            Err(err) => bail!("restore_snapshot lock error: {}", err),
+        };
+        Ok(())
+    }
+    /// Saves a copy of the ledger, async pool, events, owned addresses and variables under
+    /// the given name
+    pub(crate) fn snapshot_state(&self, name: &str) -> Result<()> {
+        let context = self.take_snapshot()?;
+        let owned = match self.owned.lock() {
+            Ok(owned) => owned.clone(),
+            Err(err) => bail!("snapshot_state lock error: {}", err),
+        };
+        let variables = match self.variables.lock() {
+            Ok(variables) => variables.clone(),
+            Err(err) => bail!("snapshot_state lock error: {}", err),
+        };
+        match self.state_snapshots.lock() {
+            Ok(mut state_snapshots) => {
+                state_snapshots.insert(
+                    name.to_string(),
+                    StateSnapshot {
+                        context,
+                        owned,
+                        variables,
+                    },
+                );
+            }
+            Err(err) => bail!("snapshot_state lock error: {}", err),
+        };
+        Ok(())
+    }
+    /// Restores the state saved under the given name, the snapshot is kept so that it can be
+    /// restored again. The per-slot counters are not restored so that addresses created in the
+    /// current slot are never derived twice.
+    pub(crate) fn restore_state(&self, name: &str) -> Result<()> {
+        let snapshot = match self.state_snapshots.lock() {
+            Ok(state_snapshots) => match state_snapshots.get(name) {
+                Some(snapshot) => snapshot.clone(),
+                None => bail!("no state snapshot named {}", name),
+            },
+            Err(err) => bail!("restore_state lock error: {}", err),
+        };
+        let mut context = snapshot.context;
+        context.slot_counters = match self.slot_counters.lock() {
+            Ok(slot_counters) => *slot_counters,
+            Err(err) => bail!("restore_state lock error: {}", err),
+        };
+        self.restore_snapshot(context)?;
+        match self.owned.lock() {
+            Ok(mut owned) => *owned = snapshot.owned,
+            Err(err) => bail!("restore_state lock error: {}", err),
+        };
+        match self.variables.lock() {
+            Ok(mut variables) => *variables = snapshot.variables,
+            Err(err) => bail!("restore_state lock error: {}", err),
+        };
+        Ok(())
+    }
+    /// Sets the execution slot, the per-slot counters are reset on slot change
+    pub(crate) fn set_execution_slot(&mut self, slot: Slot) -> Result<()> {
+        if slot != self.execution_slot {
+            match self.slot_counters.lock() {
+                Ok(mut slot_counters) => *slot_counters = SlotCounters::default(),
+                Err(err) => bail!("set_execution_slot lock error: {}", err),
+            };
+        }
+        self.execution_slot = slot;
+        Ok(())
+    }
+    /// Returns a new address derived from the creator address, the execution slot and the
+    /// number of addresses already created during this slot
+    pub(crate) fn next_created_address(&self, creator_address: &str) -> Result<String> {
+        let index = match self.slot_counters.lock() {
+            Ok(mut slot_counters) => {
+                let index = slot_counters.created_addr_index;
+                slot_counters.created_addr_index += 1;
+                index
+            }
+            Err(err) => bail!("next_created_address lock error: {}", err),
+        };
+        let mut data = creator_address.as_bytes().to_vec();
+        data.extend(self.execution_slot.period.to_be_bytes());
+        data.push(self.execution_slot.thread);
+        data.extend(index.to_be_bytes());
+        Ok(format_address('S', 0, Hash::compute_from(&data).to_bytes()))
+    }
+    pub(crate) fn random_i64(&self) -> Result<i64> {
+        match self.rng.lock() {
+            Ok(mut rng) => Ok(rng.gen()),
+            Err(err) => bail!("random_i64 lock error: {}", err),
+        }
+    }
+    /// Derives a test keypair from the seed and its name, its address and public key are
+    /// saved to the `name` and `name.public_key` variables
+    pub(crate) fn create_keypair(&self, name: &str, seed: u64) -> Result<()> {
+        let secret = Hash::compute_from(format!("{}:{}", seed, name).as_bytes());
+        // versioned secret key: version 0 followed by the secret bytes
+        let mut secret_bytes = vec![0u8];
+        secret_bytes.extend(secret.into_bytes());
+        let keypair = KeyPair::from_bytes(&secret_bytes)?;
+        let public_key = keypair.get_public_key();
+        self.set_variable(name, &Address::from_public_key(&public_key).to_string())?;
+        self.set_variable(&format!("{}.public_key", name), &public_key.to_string())?;
+        match self.keypairs.lock() {
+            Ok(mut keypairs) => {
+                keypairs.insert(name.to_string(), keypair);
+                Ok(())
+            }
+            Err(err) => bail!("create_keypair lock error: {}", err),
+        }
+    }
+    /// Signs the hash of the given data with a test keypair
+    pub(crate) fn sign(&self, keypair_name: &str, data: &[u8]) -> Result<(String, String)> {
+        match self.keypairs.lock() {
+            Ok(keypairs) => match keypairs.get(keypair_name) {
+                Some(keypair) => {
+                    let signature = keypair.sign(&Hash::compute_from(data))?;
+                    Ok((signature.to_string(), keypair.get_public_key().to_string()))
+                }
+                None => bail!("keypair {} is not declared", keypair_name),
+            },
+            Err(err) => bail!("sign lock error: {}", err),
+        }
+    }
+    pub(crate) fn set_variable(&self, name: &str, value: &str) -> Result<()> {
+        match self.variables.lock() {
+            Ok(mut variables) => {
+                variables.insert(name.to_string(), value.to_string());
+                Ok(())
             }
-            Err(err) => bail!("save lock error: {}", err),
+            Err(err) => bail!("set_variable lock error: {}", err),
+        }
+    }
+    /// Replaces a `$name` reference by the value of the variable `name`,
+    /// other values are returned unchanged
+    pub(crate) fn resolve(&self, value: &str) -> Result<String> {
+        let name = match value.strip_prefix('$') {
+            Some(name) => name,
+            None => return Ok(value.to_string()),
+        };
+        match self.variables.lock() {
+            Ok(variables) => match variables.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => bail!("variable {} is not defined", name),
+            },
+            Err(err) => bail!("resolve lock error: {}", err),
         }
     }
     pub(crate) fn call_stack_push(&self, item: CallItem) -> Result<()> {
@@ -270,12 +895,24 @@ impl ExecutionContext {
     }
     pub(crate) fn set_data_entry(&self, address: &str, key: &[u8], value: &[u8]) -> Result<()> {
         match self.ledger.lock() {
-            Ok(mut ledger) => {
-                ledger.set_data_entry(address, key, value);
-                Ok(())
-            }
+            Ok(mut ledger) => ledger.set_data_entry(address, key, value),
             Err(err) => bail!("set_data_entry lock error: {}", err),
-        }
+        };
+        self.record_ledger_change(address, Some(key))
+    }
+    pub(crate) fn delete_data_entry(&self, address: &str, key: &[u8]) -> Result<()> {
+        match self.ledger.lock() {
+            Ok(mut ledger) => ledger.delete_data_entry(address, key)?,
+            Err(err) => bail!("delete_data_entry lock error: {}", err),
+        };
+        self.record_ledger_change(address, Some(key))
+    }
+    pub(crate) fn append_data_entry(&self, address: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.ledger.lock() {
+            Ok(mut ledger) => ledger.append_data_entry(address, key, value)?,
+            Err(err) => bail!("append_data_entry lock error: {}", err),
+        };
+        self.record_ledger_change(address, Some(key))
     }
     pub(crate) fn get(&self, address: &str) -> Result<Entry> {
         match self.ledger.lock() {
@@ -285,23 +922,36 @@ impl ExecutionContext {
     }
     pub(crate) fn set_module(&self, address: &str, module: &[u8]) -> Result<()> {
         match self.ledger.lock() {
-            Ok(mut ledger) => {
-                ledger.set_module(address, module);
-                Ok(())
-            }
+            Ok(mut ledger) => ledger.set_module(address, module),
             Err(err) => bail!("set_module lock error: {}", err),
-        }
+        };
+        self.record_ledger_change(address, None)
     }
     pub(crate) fn sub(&self, address: &str, amount: u64) -> Result<()> {
         match self.ledger.lock() {
-            Ok(mut ledger) => ledger.sub(address, amount),
+            Ok(mut ledger) => ledger.sub(address, amount)?,
             Err(err) => bail!("sub lock error: {}", err),
-        }
+        };
+        self.record_ledger_change(address, None)
     }
     pub(crate) fn add(&self, address: &str, amount: u64) -> Result<()> {
         match self.ledger.lock() {
-            Ok(mut ledger) => Ok(ledger.add(address, amount)?),
+            Ok(mut ledger) => ledger.add(address, amount)?,
             Err(err) => bail!("add lock error: {}", err),
+        };
+        self.record_ledger_change(address, None)
+    }
+    /// Records that an address, and optionally one of its datastore keys, was modified
+    fn record_ledger_change(&self, address: &str, key: Option<&[u8]>) -> Result<()> {
+        match self.ledger_changes.lock() {
+            Ok(mut ledger_changes) => {
+                let changed_keys = ledger_changes.entry(address.to_string()).or_default();
+                if let Some(key) = key {
+                    changed_keys.insert(key.to_vec());
+                }
+                Ok(())
+            }
+            Err(err) => bail!("record_ledger_change lock error: {}", err),
         }
     }
     pub(crate) fn callstack_to_vec(&self) -> Result<Vec<String>> {
@@ -322,6 +972,10 @@ impl ExecutionContext {
             Err(err) => bail!("own lock error: {}", err),
         }
     }
+    /// Whether the address on top of the call stack can write to the given address
+    pub(crate) fn has_write_access(&self, address: &str) -> Result<bool> {
+        Ok(self.own(address)? || self.call_stack_peek()?.address == address)
+    }
     pub(crate) fn own_insert(&self, address: &str) -> Result<()> {
         match self.owned.lock() {
             Ok(mut owned) => {
@@ -346,25 +1000,107 @@ impl ExecutionContext {
         };
         Ok(())
     }
-    pub(crate) fn push_async_message(&self, slot: Slot, message: AsyncMessage) -> Result<()> {
-        match self.async_pool.lock() {
-            Ok(mut async_pool) => async_pool
-                .entry(slot)
-                .and_modify(|list| list.push(message.clone()))
-                .or_insert_with(|| vec![message]),
-            Err(err) => bail!("push_async_message lock error: {}", err),
+    /// Sets the datastore of the operation being executed, `None` if it is not an execute_sc
+    pub(crate) fn set_op_datastore(&self, datastore: Option<OperationDatastore>) -> Result<()> {
+        match self.op_datastore.lock() {
+            Ok(mut op_datastore) => *op_datastore = datastore,
+            Err(err) => bail!("set_op_datastore lock error: {}", err),
         };
         Ok(())
     }
-    pub(crate) fn get_async_messages_to_execute(&self) -> Result<Vec<AsyncMessage>> {
-        match self.async_pool.lock() {
-            Ok(mut async_pool) => Ok(async_pool
-                .drain_filter(|&slot, _| slot <= self.execution_slot)
-                .flat_map(|(_, messages)| messages)
-                .collect()),
-            Err(err) => bail!("get_async_messages_to_execute lock error: {}", err),
+    pub(crate) fn get_op_datastore(&self) -> Result<OperationDatastore> {
+        match self.op_datastore.lock() {
+            Ok(op_datastore) => match op_datastore.as_ref() {
+                Some(datastore) => Ok(datastore.clone()),
+                None => bail!("the current execution has no operation datastore"),
+            },
+            Err(err) => bail!("get_op_datastore lock error: {}", err),
+        }
+    }
+    /// Debits the coins and the fee (max gas times gas price) of a message from its sender,
+    /// the coins are held until the message is executed or expires while the fee is burnt
+    pub(crate) fn debit_async_message(&self, message: &AsyncMessage) -> Result<u64> {
+        let fee = match message.gas.checked_mul(message.gas_price) {
+            Some(fee) => fee,
+            None => bail!("async message fee overflows"),
+        };
+        match message.coins.checked_add(fee) {
+            Some(amount) => self.sub(&message.sender_address, amount)?,
+            None => bail!("async message coins and fee overflow"),
+        };
+        Ok(fee)
+    }
+    /// Adds a message to the pool, its emission slot and index are set here
+    pub(crate) fn push_async_message(&self, mut message: AsyncMessage) -> Result<()> {
+        message.emission_slot = self.execution_slot;
+        message.can_be_executed = message.trigger.is_none();
+        message.emission_index = match self.slot_counters.lock() {
+            Ok(mut slot_counters) => {
+                let index = slot_counters.emitted_msg_index;
+                slot_counters.emitted_msg_index += 1;
+                index
+            }
+            Err(err) => bail!("push_async_message lock error: {}", err),
+        };
+        let mut async_pool = match self.async_pool.lock() {
+            Ok(async_pool) => async_pool,
+            Err(err) => bail!("push_async_message lock error: {}", err),
+        };
+        // the changes recorded so far happened before the message existed, they fire the
+        // triggers of the pending messages now and are cleared so that they cannot fire its own
+        if message.trigger.is_some() {
+            match self.ledger_changes.lock() {
+                Ok(mut ledger_changes) => {
+                    fire_triggers(&mut async_pool, &std::mem::take(&mut *ledger_changes))
+                }
+                Err(err) => bail!("push_async_message lock error: {}", err),
+            }
         }
+        async_pool.insert(message.id(), message);
+        Ok(())
     }
+    /// Removes the expired messages and the messages to execute at the current slot from the
+    /// pool, returned in that order. The ledger changes recorded since the last call, or since
+    /// a filtered message was emitted, fire the triggers of the filtered messages first.
+    /// Messages to execute are taken by decreasing gas price as long as they fit in the
+    /// asynchronous gas budget of the slot.
+    pub(crate) fn take_async_messages(&self) -> Result<(Vec<AsyncMessage>, Vec<AsyncMessage>)> {
+        let slot = self.execution_slot;
+        let mut async_pool = match self.async_pool.lock() {
+            Ok(async_pool) => async_pool,
+            Err(err) => bail!("take_async_messages lock error: {}", err),
+        };
+        let mut slot_counters = match self.slot_counters.lock() {
+            Ok(slot_counters) => slot_counters,
+            Err(err) => bail!("take_async_messages lock error: {}", err),
+        };
+        let ledger_changes = match self.ledger_changes.lock() {
+            Ok(mut ledger_changes) => std::mem::take(&mut *ledger_changes),
+            Err(err) => bail!("take_async_messages lock error: {}", err),
+        };
+        fire_triggers(&mut async_pool, &ledger_changes);
+        let expired = async_pool
+            .drain_filter(|_, message| slot >= message.validity_end)
+            .map(|(_, message)| message)
+            .collect();
+        let to_execute = async_pool
+            .drain_filter(|_, message| {
+                if message.validity_start > slot || !message.can_be_executed {
+                    return false;
+                }
+                match slot_counters.async_gas_used.checked_add(message.gas) {
+                    Some(gas_used) if gas_used <= MAX_ASYNC_GAS => {
+                        slot_counters.async_gas_used = gas_used;
+                        true
+                    }
+                    _ => false,
+                }
+            })
+            .map(|(_, message)| message)
+            .collect();
+        Ok((expired, to_execute))
+    }
+    /// Returns the messages whose validity starts in the given slot range
     pub(crate) fn get_async_messages_in(
         &self,
         start: Option<Slot>,
@@ -382,12 +1118,15 @@ impl ExecutionContext {
                 } else {
                     Bound::Unbounded
                 };
-                Ok(async_pool
-                    .range((start_bound, end_bound))
-                    .flat_map(|(_, messages)| messages.clone())
-                    .collect())
+                let mut messages: Vec<AsyncMessage> = async_pool
+                    .values()
+                    .filter(|message| (start_bound, end_bound).contains(&message.validity_start))
+                    .cloned()
+                    .collect();
+                messages.sort_by_key(|message| message.validity_start);
+                Ok(messages)
             }
-            Err(err) => bail!("get_async_messages_to_execute lock error: {}", err),
+            Err(err) => bail!("get_async_messages_in lock error: {}", err),
         }
     }
     pub(crate) fn update_execution_trace(&self, json: JsonValue) -> Result<()> {
@@ -401,12 +1140,19 @@ impl ExecutionContext {
             Err(err) => bail!("update_execution_trace lock error: {}", err),
         }
     }
-    pub(crate) fn push_event(&self, slot: Slot, addr: String, data: String) -> Result<()> {
+    pub(crate) fn push_event(
+        &self,
+        slot: Slot,
+        addr: String,
+        data: String,
+        is_error: bool,
+    ) -> Result<()> {
         match self.event_pool.lock() {
             Ok(mut event_pool) => {
                 let event = Event {
                     sender_address: addr,
                     data,
+                    is_error,
                 };
                 event_pool
                     .entry(slot)
@@ -422,6 +1168,18 @@ impl ExecutionContext {
         start: Option<Slot>,
         end: Option<Slot>,
     ) -> Result<Vec<Event>> {
+        Ok(self
+            .get_slot_events_in(start, end)?
+            .into_iter()
+            .flat_map(|(_, events)| events)
+            .collect())
+    }
+    /// Returns the events emitted from `start` included to `end` excluded, grouped by slot
+    pub(crate) fn get_slot_events_in(
+        &self,
+        start: Option<Slot>,
+        end: Option<Slot>,
+    ) -> Result<Vec<(Slot, Vec<Event>)>> {
         match self.event_pool.lock() {
             Ok(event_pool) => {
                 let start_bound = if let Some(start) = start {
@@ -436,10 +1194,27 @@ impl ExecutionContext {
                 };
                 Ok(event_pool
                     .range((start_bound, end_bound))
-                    .flat_map(|(_, events)| events.clone())
+                    .map(|(slot, events)| (*slot, events.clone()))
                     .collect())
             }
-            Err(err) => bail!("get_events_in lock error: {}", err),
+            Err(err) => bail!("get_slot_events_in lock error: {}", err),
+        }
+    }
+    /// Returns the compiled module of the given bytecode, from the module cache if possible,
+    /// and traces the loading
+    pub(crate) fn load_module(&self, bytecode: &[u8], gas: u64) -> Result<RuntimeModule> {
+        let (module, json) = match self.module_cache.lock() {
+            Ok(mut module_cache) => module_cache.load(bytecode, gas, self.gas_costs.clone())?,
+            Err(err) => bail!("load_module lock error: {}", err),
+        };
+        self.update_execution_trace(json)?;
+        Ok(module)
+    }
+    /// Returns the module cache statistics, printed apart from the trace
+    pub(crate) fn module_cache_summary(&self) -> Result<String> {
+        match self.module_cache.lock() {
+            Ok(module_cache) => Ok(module_cache.summary()),
+            Err(err) => bail!("module_cache_summary lock error: {}", err),
         }
     }
     pub(crate) fn take_execution_trace(&self) -> Result<JsonValue> {
@@ -453,3 +1228,216 @@ impl ExecutionContext {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ABI_GAS_COSTS_PATH, WASM_GAS_COSTS_PATH};
+
+    fn exec_context() -> ExecutionContext {
+        let gas_costs =
+            GasCosts::new(ABI_GAS_COSTS_PATH.into(), WASM_GAS_COSTS_PATH.into()).unwrap();
+        ExecutionContext::from_ledger(0, gas_costs, Default::default()).unwrap()
+    }
+
+    /// Message executable from the current slot until period 10
+    fn message(target_handler: &str, trigger: Option<AsyncMessageTrigger>) -> AsyncMessage {
+        AsyncMessage {
+            target_address: "sc_address".to_string(),
+            target_handler: target_handler.to_string(),
+            validity_end: Slot {
+                period: 10,
+                thread: 0,
+            },
+            gas: 1000,
+            trigger,
+            ..Default::default()
+        }
+    }
+
+    fn trigger(address: &str, datastore_key: Option<&[u8]>) -> Option<AsyncMessageTrigger> {
+        Some(AsyncMessageTrigger {
+            address: address.to_string(),
+            datastore_key: datastore_key.map(|key| key.to_vec()),
+        })
+    }
+
+    /// Returns the target handlers of the messages to execute at the current slot
+    fn take_handlers(exec_context: &ExecutionContext) -> Vec<String> {
+        let (_, to_execute) = exec_context.take_async_messages().unwrap();
+        to_execute
+            .into_iter()
+            .map(|message| message.target_handler)
+            .collect()
+    }
+
+    #[test]
+    fn take_async_messages_by_price_within_budget() {
+        let slot = Slot {
+            period: 5,
+            thread: 0,
+        };
+        // messages as (target handler, gas, gas price, validity end), expected expired and
+        // executed handlers at period 5
+        #[allow(clippy::type_complexity)]
+        let cases: Vec<(Vec<(&str, u64, u64, Slot)>, Vec<&str>, Vec<&str>)> = vec![
+            // decreasing gas price, then emission order
+            (
+                vec![
+                    ("low", 1000, 1, slot.next()),
+                    ("high", 1000, 3, slot.next()),
+                    ("mid", 1000, 2, slot.next()),
+                    ("mid_later", 1000, 2, slot.next()),
+                ],
+                vec![],
+                vec!["high", "mid", "mid_later", "low"],
+            ),
+            // a message exceeding the remaining budget is skipped, smaller ones still fit
+            (
+                vec![
+                    ("large", MAX_ASYNC_GAS - 100, 3, slot.next()),
+                    ("too_large", 1000, 2, slot.next()),
+                    ("small", 100, 1, slot.next()),
+                ],
+                vec![],
+                vec!["large", "small"],
+            ),
+            (
+                vec![("over_budget", MAX_ASYNC_GAS + 1, 1, slot.next())],
+                vec![],
+                vec![],
+            ),
+            // the validity end is excluded from the validity window
+            (
+                vec![
+                    ("ends_now", 1000, 2, slot),
+                    ("ended", 1000, 1, Slot::default()),
+                    ("ends_next", 1000, 1, slot.next()),
+                ],
+                vec!["ends_now", "ended"],
+                vec!["ends_next"],
+            ),
+        ];
+        for (messages, expected_expired, expected_executed) in cases {
+            let mut exec_context = exec_context();
+            for (target_handler, gas, gas_price, validity_end) in messages {
+                exec_context
+                    .push_async_message(AsyncMessage {
+                        gas,
+                        gas_price,
+                        validity_end,
+                        ..message(target_handler, None)
+                    })
+                    .unwrap();
+            }
+            exec_context.set_execution_slot(slot).unwrap();
+            let (expired, to_execute) = exec_context.take_async_messages().unwrap();
+            let handlers = |messages: Vec<AsyncMessage>| -> Vec<String> {
+                messages
+                    .into_iter()
+                    .map(|message| message.target_handler)
+                    .collect()
+            };
+            assert_eq!(handlers(expired), expected_expired);
+            assert_eq!(handlers(to_execute), expected_executed);
+        }
+    }
+
+    #[test]
+    fn changes_before_emission_do_not_fire_triggers() {
+        let exec_context = exec_context();
+        exec_context
+            .set_data_entry("watched", b"key", &[1])
+            .unwrap();
+        exec_context.set_module("watched", &[0]).unwrap();
+        exec_context
+            .push_async_message(message("any_change", trigger("watched", None)))
+            .unwrap();
+        exec_context
+            .push_async_message(message("key_change", trigger("watched", Some(b"key"))))
+            .unwrap();
+        assert!(take_handlers(&exec_context).is_empty());
+        assert_eq!(
+            exec_context
+                .get_async_messages_in(None, None)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn changes_after_emission_fire_triggers() {
+        let exec_context = exec_context();
+        exec_context
+            .push_async_message(message("any_change", trigger("watched", None)))
+            .unwrap();
+        exec_context
+            .push_async_message(message("key_change", trigger("watched", Some(b"key"))))
+            .unwrap();
+        exec_context
+            .push_async_message(message("other_change", trigger("other", None)))
+            .unwrap();
+
+        // a change of another key only fires the triggers without key
+        exec_context
+            .set_data_entry("watched", b"other_key", &[1])
+            .unwrap();
+        assert_eq!(take_handlers(&exec_context), vec!["any_change"]);
+
+        exec_context
+            .set_data_entry("watched", b"key", &[1])
+            .unwrap();
+        assert_eq!(take_handlers(&exec_context), vec!["key_change"]);
+        assert_eq!(
+            exec_context
+                .get_async_messages_in(None, None)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn changes_before_a_later_emission_fire_pending_triggers() {
+        let exec_context = exec_context();
+        exec_context
+            .push_async_message(message("first", trigger("watched", None)))
+            .unwrap();
+        exec_context
+            .set_data_entry("watched", b"key", &[1])
+            .unwrap();
+        // the change fires the pending message, not the one emitted after it
+        exec_context
+            .push_async_message(message("second", trigger("watched", None)))
+            .unwrap();
+        assert_eq!(take_handlers(&exec_context), vec!["first"]);
+    }
+
+    #[test]
+    fn rollback_restores_unfired_triggers() {
+        let exec_context = exec_context();
+        exec_context
+            .push_async_message(message("watching", trigger("watched", None)))
+            .unwrap();
+        let snapshot = exec_context.take_snapshot().unwrap();
+        // a failed execution modifies the address and emits a filtered message, which fires
+        // the pending trigger, then is rolled back
+        exec_context
+            .set_data_entry("watched", b"key", &[1])
+            .unwrap();
+        exec_context
+            .push_async_message(message("rolled_back", trigger("watched", None)))
+            .unwrap();
+        exec_context.restore_snapshot(snapshot).unwrap();
+
+        assert!(take_handlers(&exec_context).is_empty());
+        let pending = exec_context.get_async_messages_in(None, None).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].target_handler, "watching");
+        assert!(!pending[0].can_be_executed);
+
+        exec_context.set_module("watched", &[0]).unwrap();
+        assert_eq!(take_handlers(&exec_context), vec!["watching"]);
+    }
+}
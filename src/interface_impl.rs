@@ -1,10 +1,13 @@
-use crate::execution_context::{AsyncMessage, ExecutionContext, Slot};
+use crate::execution_context::{AsyncMessage, AsyncMessageTrigger, ExecutionContext, Slot};
 
 use anyhow::{bail, Result};
 use json::object;
 use massa_hash::Hash;
+use massa_models::address::Address;
 use massa_sc_runtime::{Interface, InterfaceClone};
-use rand::RngCore;
+use massa_signature::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeSet, str::FromStr};
 
 impl InterfaceClone for ExecutionContext {
     fn clone_box(&self) -> Box<dyn Interface> {
@@ -69,10 +72,7 @@ impl Interface for ExecutionContext {
 
     /// Creates a new address that contains the sent bytecode
     fn create_module(&self, module: &[u8]) -> Result<String> {
-        let mut rbytes = [0; 128];
-        rand::thread_rng().fill_bytes(&mut rbytes);
-        let hash = Hash::compute_from(&rbytes);
-        let address = hash.to_bs58_check();
+        let address = self.next_created_address(&self.call_stack_peek()?.address)?;
 
         self.set_module(&address, module)?;
         self.own_insert(&address)?;
@@ -100,12 +100,38 @@ impl Interface for ExecutionContext {
         Ok(data)
     }
 
+    /// Returns the datastore keys of the current address starting with the given prefix
+    fn get_keys(&self, prefix: Option<&[u8]>) -> Result<BTreeSet<Vec<u8>>> {
+        let keys = self.get(&self.call_stack_peek()?.address)?.get_keys(prefix);
+        let json = object!(
+            get_keys: {
+                prefix: prefix,
+                return_value: keys.iter().cloned().collect::<Vec<_>>()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(keys)
+    }
+
+    /// Returns the datastore keys of the given address starting with the given prefix
+    fn get_keys_for(&self, address: &str, prefix: Option<&[u8]>) -> Result<BTreeSet<Vec<u8>>> {
+        let keys = self.get(address)?.get_keys(prefix);
+        let json = object!(
+            get_keys_for: {
+                address: address,
+                prefix: prefix,
+                return_value: keys.iter().cloned().collect::<Vec<_>>()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(keys)
+    }
+
     /// Requires to replace the data in the current address
     ///
     /// Note:
     /// The execution lib will allways use the current context address for the update
     fn raw_set_data_for(&self, address: &str, key: &[u8], value: &[u8]) -> Result<()> {
-        let curr_address = self.call_stack_peek()?.address;
         let json = object!(
             raw_set_data_for: {
                 address: address,
@@ -114,7 +140,7 @@ impl Interface for ExecutionContext {
             }
         );
         self.update_execution_trace(json)?;
-        if self.own(address)? || *address == curr_address {
+        if self.has_write_access(address)? {
             self.set_data_entry(address, key, value)?;
             Ok(())
         } else {
@@ -122,6 +148,39 @@ impl Interface for ExecutionContext {
         }
     }
 
+    /// Requires to append a value to an existing data entry of the given address
+    fn raw_append_data_for(&self, address: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let json = object!(
+            raw_append_data_for: {
+                address: address,
+                key: key,
+                value: value,
+            }
+        );
+        self.update_execution_trace(json)?;
+        if self.has_write_access(address)? {
+            self.append_data_entry(address, key, value)
+        } else {
+            bail!("you do not have write access to this entry")
+        }
+    }
+
+    /// Requires to delete an existing data entry of the given address
+    fn raw_delete_data_for(&self, address: &str, key: &[u8]) -> Result<()> {
+        let json = object!(
+            raw_delete_data_for: {
+                address: address,
+                key: key,
+            }
+        );
+        self.update_execution_trace(json)?;
+        if self.has_write_access(address)? {
+            self.delete_data_entry(address, key)
+        } else {
+            bail!("you do not have write access to this entry")
+        }
+    }
+
     fn raw_get_data(&self, key: &[u8]) -> Result<Vec<u8>> {
         let data = self.get(&self.call_stack_peek()?.address)?.get_data(key);
         let json = object!(
@@ -145,6 +204,66 @@ impl Interface for ExecutionContext {
         self.set_data_entry(&self.call_stack_peek()?.address, key, value)
     }
 
+    fn raw_append_data(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let json = object!(
+            raw_append_data: {
+                key: key,
+                value: value
+            }
+        );
+        self.update_execution_trace(json)?;
+        self.append_data_entry(&self.call_stack_peek()?.address, key, value)
+    }
+
+    fn raw_delete_data(&self, key: &[u8]) -> Result<()> {
+        let json = object!(
+            raw_delete_data: {
+                key: key
+            }
+        );
+        self.update_execution_trace(json)?;
+        self.delete_data_entry(&self.call_stack_peek()?.address, key)
+    }
+
+    /// Returns the keys of the operation datastore
+    fn get_op_keys(&self) -> Result<Vec<Vec<u8>>> {
+        let keys: Vec<Vec<u8>> = self.get_op_datastore()?.into_keys().collect();
+        let json = object!(
+            get_op_keys: {
+                return_value: keys.clone()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(keys)
+    }
+
+    fn has_op_key(&self, key: &[u8]) -> Result<bool> {
+        let ret_bool = self.get_op_datastore()?.contains_key(key);
+        let json = object!(
+            has_op_key: {
+                key: key,
+                return_value: ret_bool
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(ret_bool)
+    }
+
+    fn get_op_data(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let data = match self.get_op_datastore()?.remove(key) {
+            Some(data) => data,
+            None => bail!("operation datastore has no entry for key {:?}", key),
+        };
+        let json = object!(
+            get_op_data: {
+                key: key,
+                return_value: data.clone()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(data)
+    }
+
     /// Transfer coins from the current address to a target address
     /// to_address: target address
     /// raw_amount: amount to transfer (in raw u64)
@@ -215,7 +334,7 @@ impl Interface for ExecutionContext {
 
     fn generate_event(&self, data: String) -> Result<()> {
         let sender = self.call_stack_peek()?.address;
-        self.push_event(self.execution_slot, sender, data.clone())?;
+        self.push_event(self.execution_slot, sender, data.clone(), false)?;
         let json = object!(
             generate_event: {
                 return_value: data
@@ -248,11 +367,9 @@ impl Interface for ExecutionContext {
         Ok(ret_bool)
     }
 
+    /// Returns the Blake3 hash of the given bytes
     fn hash(&self, key: &[u8]) -> Result<[u8; 32]> {
-        let mut rbytes = [0; 128];
-        rand::thread_rng().fill_bytes(&mut rbytes);
-        let hash = Hash::compute_from(&rbytes);
-
+        let hash = Hash::compute_from(key);
         let json = object!(
             hash: {
                 key: key,
@@ -263,6 +380,54 @@ impl Interface for ExecutionContext {
         Ok(hash.into_bytes())
     }
 
+    /// Returns the SHA-256 hash of the given bytes
+    fn hash_sha256(&self, bytes: &[u8]) -> Result<[u8; 32]> {
+        let hash: [u8; 32] = Sha256::digest(bytes).into();
+        let json = object!(
+            hash_sha256: {
+                bytes: bytes,
+                return_value: hash.to_vec()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(hash)
+    }
+
+    /// Verifies a signature of the hash of the given data, invalid inputs are not verified
+    fn signature_verify(&self, data: &[u8], signature: &str, public_key: &str) -> Result<bool> {
+        let verified = match (
+            Signature::from_str(signature),
+            PublicKey::from_str(public_key),
+        ) {
+            (Ok(signature), Ok(public_key)) => public_key
+                .verify_signature(&Hash::compute_from(data), &signature)
+                .is_ok(),
+            _ => false,
+        };
+        let json = object!(
+            signature_verify: {
+                data: data,
+                signature: signature,
+                public_key: public_key,
+                return_value: verified
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(verified)
+    }
+
+    fn address_from_public_key(&self, public_key: &str) -> Result<String> {
+        let address = Address::from_public_key(&PublicKey::from_str(public_key)?).to_string();
+        let json = object!(
+            address_from_public_key: {
+                public_key: public_key,
+                return_value: address.clone()
+            }
+        );
+        self.update_execution_trace(json)?;
+        Ok(address)
+    }
+
     fn raw_set_bytecode_for(&self, address: &str, bytecode: &[u8]) -> Result<()> {
         self.set_module(address, bytecode)?;
         let json = object!(
@@ -287,7 +452,7 @@ impl Interface for ExecutionContext {
     }
 
     fn unsafe_random(&self) -> Result<i64> {
-        let rnbr: i64 = rand::random();
+        let rnbr = self.random_i64()?;
         let json = object!(
             unsafe_random: {
                 return_value: rnbr
@@ -327,23 +492,33 @@ impl Interface for ExecutionContext {
         gas_price: u64,
         coins: u64,
         data: &[u8],
-        _filter: Option<(&str, Option<&[u8]>)>,
+        filter: Option<(&str, Option<&[u8]>)>,
     ) -> Result<()> {
         let sender = self.call_stack_peek()?.address;
-        self.push_async_message(
-            Slot {
+        let message = AsyncMessage {
+            sender_address: sender.clone(),
+            target_address: target_address.to_string(),
+            target_handler: target_handler.to_string(),
+            validity_start: Slot {
                 period: validity_start.0,
                 thread: validity_start.1,
             },
-            AsyncMessage {
-                sender_address: sender.clone(),
-                target_address: target_address.to_string(),
-                target_handler: target_handler.to_string(),
-                gas: max_gas,
-                coins,
-                data: data.to_vec(),
+            validity_end: Slot {
+                period: validity_end.0,
+                thread: validity_end.1,
             },
-        )?;
+            gas: max_gas,
+            gas_price,
+            coins,
+            data: data.to_vec(),
+            trigger: filter.map(|(address, datastore_key)| AsyncMessageTrigger {
+                address: address.to_string(),
+                datastore_key: datastore_key.map(|key| key.to_vec()),
+            }),
+            ..Default::default()
+        };
+        let fee = self.debit_async_message(&message)?;
+        self.push_async_message(message)?;
         let json = object!(
             send_message: {
                 sender_address: sender,
@@ -360,7 +535,12 @@ impl Interface for ExecutionContext {
                 max_gas: max_gas,
                 gas_price: gas_price,
                 coins: coins,
+                fee: fee,
                 data: data,
+                filter: filter.map(|(address, datastore_key)| object!(
+                    address: address,
+                    datastore_key: datastore_key,
+                )),
             }
         );
         self.update_execution_trace(json)?;
@@ -0,0 +1,25 @@
+//! `massa-sc-tester` runs massa smart contracts on a simulated chain.
+//!
+//! The [`Simulator`] drives the simulated chain from Rust code, while [`run`] runs the
+//! execution configs described in the README and backs the `massa-sc-tester` binary.
+#![warn(unused_crate_dependencies)]
+#![feature(btree_drain_filter)]
+#![allow(clippy::from_over_into)]
+
+mod args;
+mod assertion;
+mod constants;
+mod execution_context;
+mod interface_impl;
+mod module_cache;
+mod report;
+mod runner;
+mod server;
+mod simulator;
+mod step_config;
+mod step_manager;
+
+pub use execution_context::{AsyncMessage, AsyncMessageTrigger, Entry, Event, Slot};
+pub use massa_sc_runtime::GasCosts;
+pub use runner::{run, CommandArguments};
+pub use simulator::{ExecutionOutput, ReadOnlyCallOutput, Simulator};
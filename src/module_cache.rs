@@ -0,0 +1,146 @@
+use crate::constants::RUNTIME_VERSION;
+use crate::execution_context::ContextPaths;
+use anyhow::{bail, Result};
+use json::{object, JsonValue};
+use massa_hash::Hash;
+use massa_sc_runtime::{Compiler, GasCosts, RuntimeModule};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Index of the next temporary module file of the process
+static TMP_FILE_INDEX: AtomicU64 = AtomicU64::new(0);
+
+/// Compiled modules shared by the scenarios of a run, keyed by the hash of their compilation
+/// key and bytecode
+pub(crate) type CompiledModules = Arc<Mutex<BTreeMap<String, RuntimeModule>>>;
+
+/// Module cache of an execution context, over the compiled modules of the run and optionally a
+/// directory the modules are saved to, to be reused by the next runs
+#[derive(Default)]
+pub(crate) struct ModuleCache {
+    modules: CompiledModules,
+    /// Directory of the serialized modules, nothing is saved if None
+    dir: Option<PathBuf>,
+    /// Runtime version and gas costs the modules are compiled with, part of the keys since gas
+    /// metering is compiled into the modules
+    compilation_key: Vec<u8>,
+    hits: u64,
+    misses: u64,
+    load_time: Duration,
+}
+
+impl ModuleCache {
+    pub(crate) fn new(paths: &ContextPaths, modules: CompiledModules) -> Result<ModuleCache> {
+        if let Some(dir) = &paths.module_cache {
+            fs::create_dir_all(dir)?;
+        }
+        let mut compilation_key = RUNTIME_VERSION.as_bytes().to_vec();
+        compilation_key.extend(fs::read(&paths.abi_gas_costs)?);
+        compilation_key.extend(fs::read(&paths.wasm_gas_costs)?);
+        Ok(ModuleCache {
+            modules,
+            dir: paths.module_cache.clone(),
+            compilation_key,
+            ..Default::default()
+        })
+    }
+
+    /// Returns the compiled module of the given bytecode with the trace of its loading,
+    /// the bytecode is only compiled if it is neither in memory nor on disk
+    pub(crate) fn load(
+        &mut self,
+        bytecode: &[u8],
+        gas: u64,
+        gas_costs: GasCosts,
+    ) -> Result<(RuntimeModule, JsonValue)> {
+        let start = Instant::now();
+        let key = Hash::compute_from(&[self.compilation_key.as_slice(), bytecode].concat())
+            .to_bs58_check();
+        let in_memory = match self.modules.lock() {
+            Ok(modules) => modules.get(&key).cloned(),
+            Err(err) => bail!("load lock error: {}", err),
+        };
+        // the lock is released while compiling so that the other scenarios are not blocked
+        let (module, cache) = match in_memory {
+            Some(module) => (module, "memory"),
+            None => {
+                let (module, cache) = self.load_saved_or_compile(&key, bytecode, gas, gas_costs)?;
+                match self.modules.lock() {
+                    Ok(mut modules) => modules.insert(key, module.clone()),
+                    Err(err) => bail!("load lock error: {}", err),
+                };
+                (module, cache)
+            }
+        };
+        if cache == "miss" {
+            self.misses += 1;
+        } else {
+            self.hits += 1;
+        }
+        self.load_time += start.elapsed();
+        // the timings and statistics are reported apart to keep the trace reproducible
+        let json = object!(
+            load_module: {
+                bytecode_hash: Hash::compute_from(bytecode).to_bs58_check(),
+                cache: cache,
+            }
+        );
+        Ok((module, json))
+    }
+
+    /// Returns the module saved to the cache directory if any, otherwise compiles and saves it
+    fn load_saved_or_compile(
+        &self,
+        key: &str,
+        bytecode: &[u8],
+        gas: u64,
+        gas_costs: GasCosts,
+    ) -> Result<(RuntimeModule, &'static str)> {
+        let path = self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.bin", key)));
+        // files are renamed into place once fully written, a file that fails to deserialize
+        // is a miss, the module is compiled again and the file replaced
+        let saved_module =
+            path.as_ref()
+                .and_then(|path| fs::read(path).ok())
+                .and_then(|ser_module| {
+                    RuntimeModule::deserialize(&ser_module, gas, gas_costs.clone()).ok()
+                });
+        if let Some(module) = saved_module {
+            return Ok((module, "disk"));
+        }
+        let module = RuntimeModule::new(bytecode, gas, gas_costs, Compiler::CL)?;
+        if let Some(path) = &path {
+            // the directory is shared by the threads and processes of other runs,
+            // which must never read a partially written module
+            let tmp_path = path.with_extension(format!(
+                "{}.{}.tmp",
+                std::process::id(),
+                TMP_FILE_INDEX.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&tmp_path, module.serialize()?)?;
+            fs::rename(&tmp_path, path)?;
+        }
+        Ok((module, "miss"))
+    }
+
+    /// Returns a summary of the cache hits and misses and of the time spent loading modules
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "module cache: {} hit(s), {} miss(es), {} ms loading modules",
+            self.hits,
+            self.misses,
+            self.load_time.as_millis()
+        )
+    }
+}
@@ -0,0 +1,187 @@
+use crate::execution_context::Slot;
+use anyhow::{bail, Result};
+use json::JsonValue;
+use std::{fmt::Write as _, fs, path::Path, str::FromStr};
+
+/// Format of the test report
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ReportFormat {
+    Junit,
+    Tap,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "junit" => Ok(ReportFormat::Junit),
+            "tap" => Ok(ReportFormat::Tap),
+            _ => bail!("unknown report format {}, expected junit or tap", format),
+        }
+    }
+}
+
+/// Result of a named step
+pub(crate) struct CaseReport {
+    pub name: String,
+    /// Failure message if the step is an assertion that did not hold or an execution that failed
+    pub failure: Option<String>,
+    /// Trace of the step, kept for failed steps only
+    pub trace: Option<JsonValue>,
+    /// Gas consumed by the step if it executes a smart contract
+    pub gas_used: Option<u64>,
+}
+
+/// Results of the steps of a slot of a scenario
+pub(crate) struct SuiteReport {
+    /// Path of the execution config
+    pub scenario: String,
+    pub slot: Slot,
+    pub cases: Vec<CaseReport>,
+}
+
+impl SuiteReport {
+    fn name(&self) -> String {
+        format!(
+            "{} slot ({}, {})",
+            self.scenario, self.slot.period, self.slot.thread
+        )
+    }
+    fn failures(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.failure.is_some())
+            .count()
+    }
+}
+
+/// Escapes the XML special characters of a text or attribute value
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a JUnit XML report, each slot being a test suite
+fn to_junit(name: &str, suites: &[SuiteReport]) -> Result<String> {
+    let tests: usize = suites.iter().map(|suite| suite.cases.len()).sum();
+    let failures: usize = suites.iter().map(SuiteReport::failures).sum();
+    let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        report,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\">",
+        escape_xml(name),
+        tests,
+        failures
+    )?;
+    for suite in suites {
+        writeln!(
+            report,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">",
+            escape_xml(&suite.name()),
+            suite.cases.len(),
+            suite.failures()
+        )?;
+        for case in &suite.cases {
+            writeln!(
+                report,
+                "    <testcase name=\"{}\" classname=\"{}\">",
+                escape_xml(&case.name),
+                escape_xml(&suite.name())
+            )?;
+            if let Some(gas_used) = case.gas_used {
+                writeln!(report, "      <properties>")?;
+                writeln!(
+                    report,
+                    "        <property name=\"gas_used\" value=\"{}\"/>",
+                    gas_used
+                )?;
+                writeln!(report, "      </properties>")?;
+            }
+            if let Some(failure) = &case.failure {
+                let trace = case
+                    .trace
+                    .as_ref()
+                    .map(|trace| trace.pretty(4))
+                    .unwrap_or_default();
+                writeln!(
+                    report,
+                    "      <failure message=\"{}\">{}</failure>",
+                    escape_xml(failure),
+                    escape_xml(&trace)
+                )?;
+            }
+            writeln!(report, "    </testcase>")?;
+        }
+        writeln!(report, "  </testsuite>")?;
+    }
+    writeln!(report, "</testsuites>")?;
+    Ok(report)
+}
+
+/// Writes a TAP 14 report, each slot being a subtest
+fn to_tap(suites: &[SuiteReport]) -> Result<String> {
+    let mut report = String::from("TAP version 14\n");
+    writeln!(report, "1..{}", suites.len())?;
+    for (suite_index, suite) in suites.iter().enumerate() {
+        writeln!(report, "# Subtest: {}", suite.name())?;
+        writeln!(report, "    1..{}", suite.cases.len())?;
+        for (case_index, case) in suite.cases.iter().enumerate() {
+            let status = if case.failure.is_some() {
+                "not ok"
+            } else {
+                "ok"
+            };
+            writeln!(report, "    {} {} - {}", status, case_index + 1, case.name)?;
+            if case.failure.is_none() && case.gas_used.is_none() {
+                continue;
+            }
+            // YAML diagnostic block, JSON strings are valid YAML scalars
+            writeln!(report, "      ---")?;
+            if let Some(failure) = &case.failure {
+                writeln!(
+                    report,
+                    "      message: {}",
+                    JsonValue::from(failure.as_str()).dump()
+                )?;
+            }
+            if let Some(gas_used) = case.gas_used {
+                writeln!(report, "      gas_used: {}", gas_used)?;
+            }
+            if let Some(trace) = &case.trace {
+                writeln!(report, "      trace: |")?;
+                for line in trace.pretty(4).lines() {
+                    writeln!(report, "        {}", line)?;
+                }
+            }
+            writeln!(report, "      ...")?;
+        }
+        let status = if suite.failures() > 0 { "not ok" } else { "ok" };
+        writeln!(report, "{} {} - {}", status, suite_index + 1, suite.name())?;
+    }
+    Ok(report)
+}
+
+/// Writes the report of the run in the given format
+pub(crate) fn write_report(
+    path: &Path,
+    format: ReportFormat,
+    name: &str,
+    suites: &[SuiteReport],
+) -> Result<()> {
+    let report = match format {
+        ReportFormat::Junit => to_junit(name, suites)?,
+        ReportFormat::Tap => to_tap(suites)?,
+    };
+    Ok(fs::write(path, report)?)
+}
@@ -0,0 +1,425 @@
+use crate::constants::{
+    ABI_GAS_COSTS_PATH, LEDGER_PATH, STATE_PATH, TRACE_PATH, WASM_GAS_COSTS_PATH,
+};
+use crate::execution_context::{ContextPaths, ExecutionContext};
+use crate::module_cache::CompiledModules;
+use crate::report::{self, CaseReport, ReportFormat, SuiteReport};
+use crate::server;
+use crate::step_config::{ExecutionConfig, PathsConfig, SlotExecutionSteps, Step};
+use crate::step_manager::{execute_step, StepOutput};
+use anyhow::{bail, Result};
+use json::{object, JsonValue};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use structopt::StructOpt;
+
+/// Command line arguments of the massa-sc-tester binary
+#[derive(StructOpt)]
+pub struct CommandArguments {
+    /// Paths to the execution configs, directories are searched for .yaml and .yml files
+    #[structopt(required_unless = "serve")]
+    config_paths: Vec<PathBuf>,
+    /// Seed of the random generator, overrides the one of the execution config
+    #[structopt(long)]
+    seed: Option<u64>,
+    /// Ledger loaded at start
+    #[structopt(long)]
+    ledger_input: Option<PathBuf>,
+    /// Ledger written at the end of each step
+    #[structopt(long)]
+    ledger_output: Option<PathBuf>,
+    /// Chain state loaded at start
+    #[structopt(long)]
+    state_input: Option<PathBuf>,
+    /// Chain state written at the end of each step
+    #[structopt(long)]
+    state_output: Option<PathBuf>,
+    /// Execution trace written at the end of the run
+    #[structopt(long)]
+    trace_output: Option<PathBuf>,
+    /// ABI gas costs file
+    #[structopt(long)]
+    abi_gas_costs: Option<PathBuf>,
+    /// WASM gas costs file
+    #[structopt(long)]
+    wasm_gas_costs: Option<PathBuf>,
+    /// Directory the compiled modules are saved to and reused from by the next runs
+    #[structopt(long)]
+    module_cache: Option<PathBuf>,
+    /// Start from an empty ledger and chain state
+    #[structopt(long)]
+    empty_ledger: bool,
+    /// Do not write the ledger and chain state back
+    #[structopt(long)]
+    no_ledger_output: bool,
+    /// Test report written at the end of the run
+    #[structopt(long)]
+    report: Option<PathBuf>,
+    /// Format of the test report, junit or tap
+    #[structopt(long, default_value = "junit")]
+    report_format: ReportFormat,
+    /// Number of scenarios run in parallel, default is the number of available cores
+    #[structopt(long)]
+    jobs: Option<usize>,
+    /// Serves the node JSON-RPC API on the given address, e.g. 127.0.0.1:33035,
+    /// instead of running execution configs
+    #[structopt(long)]
+    serve: Option<String>,
+    /// Interval between two slots in serve mode in milliseconds, slots are only advanced on
+    /// demand if omitted
+    #[structopt(long)]
+    slot_interval: Option<u64>,
+}
+
+/// Resolves the files of the run, the command line options take precedence over the config,
+/// an isolated scenario only loads the ledger and state it is given and writes nothing back
+fn context_paths(args: &CommandArguments, config: &PathsConfig, isolated: bool) -> ContextPaths {
+    let given = |arg: &Option<PathBuf>, config: &Option<String>| {
+        arg.clone().or_else(|| config.as_ref().map(PathBuf::from))
+    };
+    let path = |arg: &Option<PathBuf>, config: &Option<String>, default: &str| {
+        given(arg, config).unwrap_or_else(|| PathBuf::from(default))
+    };
+    let input = |arg: &Option<PathBuf>, config: &Option<String>, default: &str| {
+        if isolated {
+            given(arg, config)
+        } else {
+            // the default file is only loaded if it exists, a given one must exist
+            given(arg, config)
+                .or_else(|| Some(PathBuf::from(default)).filter(|path| path.is_file()))
+        }
+    };
+    let load = !(args.empty_ledger || config.empty_ledger);
+    let write = !isolated && !args.no_ledger_output && config.write_ledger.unwrap_or(true);
+    ContextPaths {
+        ledger_input: load
+            .then(|| input(&args.ledger_input, &config.ledger_input, LEDGER_PATH))
+            .flatten(),
+        ledger_output: write.then(|| path(&args.ledger_output, &config.ledger_output, LEDGER_PATH)),
+        state_input: load
+            .then(|| input(&args.state_input, &config.state_input, STATE_PATH))
+            .flatten(),
+        state_output: write.then(|| path(&args.state_output, &config.state_output, STATE_PATH)),
+        abi_gas_costs: path(
+            &args.abi_gas_costs,
+            &config.abi_gas_costs,
+            ABI_GAS_COSTS_PATH,
+        ),
+        wasm_gas_costs: path(
+            &args.wasm_gas_costs,
+            &config.wasm_gas_costs,
+            WASM_GAS_COSTS_PATH,
+        ),
+        module_cache: args
+            .module_cache
+            .clone()
+            .or_else(|| config.module_cache.as_ref().map(PathBuf::from)),
+    }
+}
+
+/// Lists the execution configs of the given paths, directories are searched recursively for
+/// .yaml and .yml files, the .json files next to them being ledgers, states and traces
+fn discover_scenarios(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut scenarios = Vec::new();
+    for path in paths {
+        if path.is_file() {
+            scenarios.push(path.clone());
+        } else if path.is_dir() {
+            let mut dir_scenarios = Vec::new();
+            let mut dirs = vec![path.clone()];
+            while let Some(dir) = dirs.pop() {
+                for dir_entry in fs::read_dir(&dir)? {
+                    let dir_entry = dir_entry?;
+                    let entry_path = dir_entry.path();
+                    let extension = entry_path.extension().and_then(|ext| ext.to_str());
+                    // the entry file type does not follow symlinks, so symlinked directories
+                    // are skipped and cannot loop
+                    if dir_entry.file_type()?.is_dir() {
+                        dirs.push(entry_path);
+                    } else if matches!(extension, Some("yaml") | Some("yml")) {
+                        dir_scenarios.push(entry_path);
+                    }
+                }
+            }
+            dir_scenarios.sort();
+            scenarios.extend(dir_scenarios);
+        } else {
+            bail!("{} isn't a file or a directory", path.display())
+        }
+    }
+    Ok(scenarios)
+}
+
+/// Trace and step results of a scenario
+struct ScenarioOutput {
+    trace: JsonValue,
+    /// Trace output of the scenario config, if any
+    trace_path: Option<PathBuf>,
+    suites: Vec<SuiteReport>,
+    /// Module cache statistics, printed in the scenarios order
+    cache_summary: String,
+    /// Seed drawn when neither the command line nor the config gave one, printed so that the
+    /// scenario can be replayed with `--seed`
+    random_seed: Option<u64>,
+}
+
+/// Runs the scenario of the given execution config in its own execution context, an isolated
+/// scenario starts from the ledger and state of its config, or empty ones, and writes nothing back
+fn run_scenario(
+    args: &CommandArguments,
+    path: &Path,
+    isolated: bool,
+    modules: &CompiledModules,
+) -> Result<ScenarioOutput> {
+    // parse the config file
+    let extension = path.extension().unwrap_or_default();
+    let config_slice = fs::read(path)?;
+    let execution_config: ExecutionConfig = match extension.to_str() {
+        Some("yaml") | Some("yml") => serde_yaml::from_slice(&config_slice)?,
+        Some("json") => serde_json::from_slice(&config_slice)?,
+        _ => bail!(
+            "{} extension should be .yaml, .yml or .json",
+            path.display()
+        ),
+    };
+
+    // create the context
+    let given_seed = args.seed.or(execution_config.seed);
+    let seed = given_seed.unwrap_or_else(rand::random);
+    let paths = context_paths(args, &execution_config.paths, isolated);
+    let mut exec_context = ExecutionContext::new(seed, paths, modules.clone())?;
+    for name in &execution_config.keypairs {
+        exec_context.create_keypair(name, seed)?;
+    }
+
+    // execute the steps
+    let scenario = path.display().to_string();
+    let mut trace = JsonValue::new_array();
+    let mut suites = Vec::new();
+    for SlotExecutionSteps {
+        slot,
+        execution_steps,
+    } in execution_config.executions
+    {
+        exec_context.set_execution_slot(slot)?;
+        let mut slot_trace = JsonValue::new_array();
+        let mut cases = Vec::new();
+        for Step { name, config } in execution_steps {
+            let StepOutput {
+                trace: step_trace,
+                failure,
+                gas_used,
+            } = execute_step(&mut exec_context, config)?;
+            cases.push(CaseReport {
+                name: name.clone(),
+                trace: failure.is_some().then(|| step_trace.clone()),
+                failure,
+                gas_used,
+            });
+            slot_trace.push(object!(
+                execute_step: {
+                    name: name,
+                    output: step_trace
+                }
+            ))?;
+        }
+        trace.push(object!(
+            execute_slot: {
+                execution_slot: {
+                    period: slot.period,
+                    thread: slot.thread
+                },
+                output: slot_trace
+            }
+        ))?;
+        suites.push(SuiteReport {
+            scenario: scenario.clone(),
+            slot,
+            cases,
+        });
+    }
+    Ok(ScenarioOutput {
+        trace,
+        trace_path: execution_config.paths.trace_output.map(PathBuf::from),
+        suites,
+        cache_summary: exec_context.module_cache_summary()?,
+        random_seed: given_seed.is_none().then_some(seed),
+    })
+}
+
+/// Runs independent scenarios on a pool of threads, the results are in the scenarios order
+fn run_scenarios(
+    args: &CommandArguments,
+    scenarios: &[PathBuf],
+) -> Result<Vec<Result<ScenarioOutput>>> {
+    let jobs = args
+        .jobs
+        .or_else(|| thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+        .clamp(1, scenarios.len().max(1));
+    let next_index = AtomicUsize::new(0);
+    let modules = CompiledModules::default();
+    let results = Mutex::new(BTreeMap::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                let scenario = match scenarios.get(index) {
+                    Some(scenario) => scenario,
+                    None => break,
+                };
+                let result = run_scenario(args, scenario, true, &modules);
+                match results.lock() {
+                    Ok(mut results) => results.insert(index, result),
+                    Err(_) => break,
+                };
+            });
+        }
+    });
+    match results.into_inner() {
+        Ok(results) => Ok(results.into_values().collect()),
+        Err(err) => bail!("run_scenarios lock error: {}", err),
+    }
+}
+
+/// Runs the scenarios given on the command line, fails if any of them does,
+/// or serves the node JSON-RPC API in serve mode
+pub fn run(args: CommandArguments) -> Result<()> {
+    if let Some(address) = &args.serve {
+        let seed = match args.seed {
+            Some(seed) => seed,
+            None => {
+                let seed = rand::random();
+                eprintln!("random seed: {}", seed);
+                seed
+            }
+        };
+        let paths = context_paths(&args, &PathsConfig::default(), false);
+        let exec_context = ExecutionContext::new(seed, paths, Default::default())?;
+        let slot_interval = args.slot_interval.map(Duration::from_millis);
+        return server::serve(exec_context, address, slot_interval);
+    }
+
+    let scenarios = discover_scenarios(&args.config_paths)?;
+    if scenarios.is_empty() {
+        bail!("no execution config found")
+    }
+
+    // a single config file keeps its errors, its ledger and state files and its own trace file,
+    // a directory is always run as isolated scenarios even if it holds a single one
+    let single_file = matches!(args.config_paths.as_slice(), [path] if path.is_file());
+    if let (true, [scenario]) = (single_file, scenarios.as_slice()) {
+        let output = run_scenario(&args, scenario, false, &Default::default())?;
+        if let Some(seed) = output.random_seed {
+            eprintln!("random seed: {}", seed);
+        }
+        eprintln!("{}", output.cache_summary);
+        let trace_path = args
+            .trace_output
+            .clone()
+            .or(output.trace_path)
+            .unwrap_or_else(|| PathBuf::from(TRACE_PATH));
+        let mut file = fs::File::create(trace_path)?;
+        output.trace.write_pretty(&mut file, 4)?;
+        if let Some(report_path) = &args.report {
+            let name = scenario.display().to_string();
+            report::write_report(report_path, args.report_format, &name, &output.suites)?;
+        }
+        let failures = print_failures(&output.suites);
+        if failures > 0 {
+            bail!("{} step(s) failed", failures)
+        }
+        return Ok(());
+    }
+
+    // run every scenario from its own initial state, the traces are gathered by scenario
+    // and everything is reported in the scenarios order whatever the order of completion
+    let mut trace = JsonValue::new_object();
+    let mut suites = Vec::new();
+    let mut summary = Vec::new();
+    for (scenario, result) in scenarios.iter().zip(run_scenarios(&args, &scenarios)?) {
+        let name = scenario.display().to_string();
+        let result = match result {
+            Ok(output) => {
+                if let Some(seed) = output.random_seed {
+                    eprintln!("{}: random seed: {}", name, seed);
+                }
+                eprintln!("{}: {}", name, output.cache_summary);
+                if let Some(trace_path) = &output.trace_path {
+                    let mut file = fs::File::create(trace_path)?;
+                    output.trace.write_pretty(&mut file, 4)?;
+                }
+                trace[name.as_str()] = output.trace;
+                let failures = print_failures(&output.suites);
+                let cases: usize = output.suites.iter().map(|suite| suite.cases.len()).sum();
+                suites.extend(output.suites);
+                if failures > 0 {
+                    format!("failed ({} of {} steps)", failures, cases)
+                } else {
+                    "passed".to_string()
+                }
+            }
+            Err(err) => {
+                trace[name.as_str()] = object!(error: err.to_string());
+                format!("error ({})", err)
+            }
+        };
+        summary.push((name, result));
+    }
+    let trace_path = args
+        .trace_output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(TRACE_PATH));
+    let mut file = fs::File::create(trace_path)?;
+    trace.write_pretty(&mut file, 4)?;
+    if let Some(report_path) = &args.report {
+        report::write_report(report_path, args.report_format, "massa-sc-tester", &suites)?;
+    }
+
+    // print the summary table
+    let width = summary
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+    println!("{:width$}  result", "scenario", width = width);
+    for (name, result) in &summary {
+        println!("{:width$}  {}", name, result, width = width);
+    }
+    let failed = summary
+        .iter()
+        .filter(|(_, result)| result != "passed")
+        .count();
+    if failed > 0 {
+        bail!("{} of {} scenario(s) failed", failed, summary.len())
+    }
+    Ok(())
+}
+
+/// Prints the failed assertions and executions of a scenario and returns their number
+fn print_failures(suites: &[SuiteReport]) -> usize {
+    let failures: Vec<_> = suites
+        .iter()
+        .flat_map(|suite| suite.cases.iter().map(move |case| (suite, case)))
+        .filter_map(|(suite, case)| Some((suite, &case.name, case.failure.as_ref()?)))
+        .collect();
+    if !failures.is_empty() {
+        eprintln!("failed steps:");
+        for (suite, name, failure) in &failures {
+            eprintln!(
+                "  - {} at slot ({}, {}) of {}: {}",
+                name, suite.slot.period, suite.slot.thread, suite.scenario, failure
+            );
+        }
+    }
+    failures.len()
+}
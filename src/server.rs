@@ -0,0 +1,608 @@
+use crate::constants::THREAD_COUNT;
+use crate::execution_context::{format_address, CallItem, Entry, Event, ExecutionContext, Slot};
+use crate::step_manager::{
+    execute_async_messages, execute_function, read_only_call, ReadOnlyOutput,
+};
+use anyhow::{bail, Result};
+use json::{array, object, JsonValue};
+use massa_hash::Hash;
+use massa_models::address::Address;
+use massa_sc_runtime::Response;
+use massa_signature::PublicKey;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Request, Server};
+
+const OPERATION_TYPE_TRANSACTION: u64 = 0;
+const OPERATION_TYPE_EXECUTE_SC: u64 = 3;
+const OPERATION_TYPE_CALL_SC: u64 = 4;
+
+/// Formats raw coins as the node does, 1 raw_coin = 1e-9 coin
+fn format_amount(raw_coins: u64) -> String {
+    let fraction = format!("{:09}", raw_coins % 1_000_000_000);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        (raw_coins / 1_000_000_000).to_string()
+    } else {
+        format!("{}.{}", raw_coins / 1_000_000_000, fraction)
+    }
+}
+
+/// Reads the parameter of a JSON-RPC request as a slot, None if it is null
+fn parse_slot(value: &JsonValue) -> Result<Option<Slot>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    match (value["period"].as_u64(), value["thread"].as_u8()) {
+        (Some(period), Some(thread)) => Ok(Some(Slot { period, thread })),
+        _ => bail!("invalid slot {}", value.dump()),
+    }
+}
+
+/// Reads the parameter of a JSON-RPC request as a byte array
+fn parse_bytes(value: &JsonValue) -> Result<Vec<u8>> {
+    value
+        .members()
+        .map(|byte| match byte.as_u8() {
+            Some(byte) => Ok(byte),
+            None => bail!("invalid byte {}", byte.dump()),
+        })
+        .collect()
+}
+
+/// Converts an event to the output event format of the node
+fn event_to_json(slot: Slot, index_in_slot: usize, event: Event) -> JsonValue {
+    object!(
+        context: {
+            slot: slot,
+            block: JsonValue::Null,
+            read_only: false,
+            index_in_slot: index_in_slot,
+            call_stack: array![event.sender_address],
+            origin_operation_id: JsonValue::Null,
+            is_final: true,
+            is_error: event.is_error,
+        },
+        data: event.data,
+    )
+}
+
+/// Reader of the binary format of operations, numbers are unsigned LEB128 varints
+struct OperationReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> OperationReader<'a> {
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = match self.bytes.split_first() {
+                Some(split) => split,
+                None => bail!("truncated operation"),
+            };
+            self.bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("invalid varint in operation")
+    }
+    fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_varint()? as usize;
+        if len > self.bytes.len() {
+            bail!("truncated operation")
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes.to_vec())
+    }
+    /// Reads an address serialized as its type (0 for users, 1 for smart contracts),
+    /// its version and its hash
+    fn read_address(&mut self) -> Result<String> {
+        let prefix = match self.read_varint()? {
+            0 => 'U',
+            1 => 'S',
+            address_type => bail!("unknown address type {}", address_type),
+        };
+        let version = self.read_varint()?;
+        if self.bytes.len() < 32 {
+            bail!("truncated operation")
+        }
+        let (hash, rest) = self.bytes.split_at(32);
+        self.bytes = rest;
+        Ok(format_address(prefix, version, hash))
+    }
+}
+
+/// Operation sent to the node, the other operation types are not supported
+enum Operation {
+    Transaction {
+        recipient_address: String,
+        amount: u64,
+    },
+    ExecuteSC {
+        max_gas: u64,
+        bytecode: Vec<u8>,
+        datastore: BTreeMap<Vec<u8>, Vec<u8>>,
+    },
+    CallSC {
+        max_gas: u64,
+        coins: u64,
+        target_address: String,
+        target_function: String,
+        parameter: Vec<u8>,
+    },
+}
+
+impl Operation {
+    /// Decodes the serialized content of an operation, returns its fee and the operation
+    fn decode(content: &[u8]) -> Result<(u64, Operation)> {
+        let mut reader = OperationReader { bytes: content };
+        let fee = reader.read_varint()?;
+        let _expire_period = reader.read_varint()?;
+        let operation = match reader.read_varint()? {
+            OPERATION_TYPE_TRANSACTION => Operation::Transaction {
+                recipient_address: reader.read_address()?,
+                amount: reader.read_varint()?,
+            },
+            OPERATION_TYPE_EXECUTE_SC => {
+                let max_gas = reader.read_varint()?;
+                let bytecode = reader.read_bytes()?;
+                let mut datastore = BTreeMap::new();
+                for _ in 0..reader.read_varint()? {
+                    datastore.insert(reader.read_bytes()?, reader.read_bytes()?);
+                }
+                Operation::ExecuteSC {
+                    max_gas,
+                    bytecode,
+                    datastore,
+                }
+            }
+            OPERATION_TYPE_CALL_SC => Operation::CallSC {
+                max_gas: reader.read_varint()?,
+                coins: reader.read_varint()?,
+                target_address: reader.read_address()?,
+                target_function: String::from_utf8(reader.read_bytes()?)?,
+                parameter: reader.read_bytes()?,
+            },
+            operation_type => bail!("unsupported operation type {}", operation_type),
+        };
+        Ok((fee, operation))
+    }
+}
+
+/// Simulated node answering the JSON-RPC requests
+struct Node {
+    exec_context: ExecutionContext,
+}
+
+impl Node {
+    /// Moves to the next slot and runs its asynchronous messages
+    fn advance_slot(&mut self) -> Result<()> {
+        let slot = self.exec_context.execution_slot.next();
+        self.exec_context.set_execution_slot(slot)?;
+        let mut trace = JsonValue::new_array();
+        execute_async_messages(&self.exec_context, &mut trace)?;
+        self.exec_context.save()
+    }
+
+    fn get_addresses(&self, params: &JsonValue) -> Result<JsonValue> {
+        let mut addresses = JsonValue::new_array();
+        for address in params[0].members() {
+            let address = match address.as_str() {
+                Some(address) => address,
+                None => bail!("invalid address {}", address.dump()),
+            };
+            let entry = self.exec_context.get_entry(address).unwrap_or_default();
+            let thread = Address::from_str(address)
+                .map(|address| address.get_thread(THREAD_COUNT))
+                .unwrap_or_default();
+            let keys: Vec<JsonValue> = entry
+                .datastore
+                .keys()
+                .map(|key| key.clone().into())
+                .collect();
+            addresses.push(object!(
+                address: address,
+                thread: thread,
+                final_balance: format_amount(entry.balance),
+                candidate_balance: format_amount(entry.balance),
+                final_roll_count: 0,
+                candidate_roll_count: 0,
+                final_datastore_keys: keys.clone(),
+                candidate_datastore_keys: keys,
+            ))?;
+        }
+        Ok(addresses)
+    }
+
+    fn get_datastore_entries(&self, params: &JsonValue) -> Result<JsonValue> {
+        let mut entries = JsonValue::new_array();
+        for input in params[0].members() {
+            let address = match input["address"].as_str() {
+                Some(address) => address,
+                None => bail!("invalid datastore entry input {}", input.dump()),
+            };
+            let key = parse_bytes(&input["key"])?;
+            let value = self
+                .exec_context
+                .get_entry(address)
+                .ok()
+                .and_then(|entry| entry.datastore.get(&key).cloned());
+            entries.push(object!(
+                final_value: value.clone(),
+                candidate_value: value,
+            ))?;
+        }
+        Ok(entries)
+    }
+
+    /// Returns the events matching the filter, its slot bounds are both included
+    fn get_filtered_sc_output_event(&self, params: &JsonValue) -> Result<JsonValue> {
+        let filter = &params[0];
+        let start = parse_slot(&filter["start"])?;
+        let end = parse_slot(&filter["end"])?.map(|end| end.next());
+        let emitter_address = filter["emitter_address"].as_str();
+        let is_error = filter["is_error"].as_bool();
+        let mut events = JsonValue::new_array();
+        for (slot, slot_events) in self.exec_context.get_slot_events_in(start, end)? {
+            for (index_in_slot, event) in slot_events.into_iter().enumerate() {
+                if emitter_address.map_or(false, |address| address != event.sender_address)
+                    || is_error.map_or(false, |is_error| is_error != event.is_error)
+                {
+                    continue;
+                }
+                events.push(event_to_json(slot, index_in_slot, event))?;
+            }
+        }
+        Ok(events)
+    }
+
+    fn execute_read_only_call(&self, params: &JsonValue) -> Result<JsonValue> {
+        let mut outputs = JsonValue::new_array();
+        for call in params[0].members() {
+            let (target_address, target_function, max_gas) = match (
+                call["target_address"].as_str(),
+                call["target_function"].as_str(),
+                call["max_gas"].as_u64(),
+            ) {
+                (Some(address), Some(function), Some(max_gas)) => (address, function, max_gas),
+                _ => bail!("invalid read only call {}", call.dump()),
+            };
+            let caller_address = call["caller_address"].as_str().unwrap_or(target_address);
+            let parameter = parse_bytes(&call["parameter"])?;
+            let call_stack = vec![
+                CallItem {
+                    address: caller_address.to_string(),
+                    coins: 0,
+                },
+                CallItem {
+                    address: target_address.to_string(),
+                    coins: 0,
+                },
+            ];
+            let ReadOnlyOutput { result, events, .. } = read_only_call(
+                &self.exec_context,
+                call_stack,
+                target_address,
+                target_function,
+                &parameter,
+                max_gas,
+            )?;
+            let slot = self.exec_context.execution_slot;
+            let (result, gas_cost) = match result {
+                Ok(Response {
+                    ret, remaining_gas, ..
+                }) => (object!(Ok: ret), max_gas.saturating_sub(remaining_gas)),
+                Err(err) => (object!(Error: err.to_string()), max_gas),
+            };
+            let output_events: Vec<JsonValue> = events
+                .into_iter()
+                .enumerate()
+                .map(|(index_in_slot, event)| event_to_json(slot, index_in_slot, event))
+                .collect();
+            outputs.push(object!(
+                executed_at: slot,
+                result: result,
+                output_events: output_events,
+                gas_cost: gas_cost,
+            ))?;
+        }
+        Ok(outputs)
+    }
+
+    /// Executes transactions, smart contract executions and calls right away at the current
+    /// slot, signatures are not verified
+    fn send_operations(&mut self, params: &JsonValue) -> Result<JsonValue> {
+        // the whole batch is decoded first so that an invalid operation applies none of them
+        let mut operations = Vec::new();
+        for operation in params[0].members() {
+            let content = parse_bytes(&operation["serialized_content"])?;
+            let creator_address = match operation["creator_public_key"].as_str() {
+                Some(public_key) => {
+                    Address::from_public_key(&PublicKey::from_str(public_key)?).to_string()
+                }
+                None => bail!("operation without creator public key"),
+            };
+            let (fee, decoded) = Operation::decode(&content)?;
+            operations.push((creator_address, fee, decoded, content));
+        }
+        let mut operation_ids = JsonValue::new_array();
+        let mut result = Ok(());
+        for (creator_address, fee, operation, content) in operations {
+            result = self.execute_operation(&creator_address, fee, operation);
+            if result.is_err() {
+                break;
+            }
+            operation_ids.push(Hash::compute_from(&content).to_bs58_check())?;
+        }
+        // the operations applied before a failing one are kept, so they are saved in any case
+        self.exec_context.save()?;
+        result?;
+        Ok(operation_ids)
+    }
+
+    /// Executes an operation, a failed execution is reported by an error event
+    fn execute_operation(
+        &mut self,
+        creator_address: &str,
+        fee: u64,
+        operation: Operation,
+    ) -> Result<()> {
+        if self.exec_context.get_entry(creator_address).is_err() {
+            bail!("operation creator {} has no ledger entry", creator_address)
+        }
+        self.exec_context.sub(creator_address, fee)?;
+        let snapshot = self.exec_context.take_snapshot()?;
+        let result = match operation {
+            Operation::Transaction {
+                recipient_address,
+                amount,
+            } => {
+                if self.exec_context.get_entry(&recipient_address).is_err() {
+                    self.exec_context
+                        .create_new_entry(recipient_address.clone(), Entry::default())?;
+                }
+                self.exec_context
+                    .sub(creator_address, amount)
+                    .and_then(|_| self.exec_context.add(&recipient_address, amount))
+            }
+            Operation::ExecuteSC {
+                max_gas,
+                bytecode,
+                datastore,
+            } => {
+                let call_stack = vec![CallItem {
+                    address: creator_address.to_string(),
+                    coins: 0,
+                }];
+                execute_function(
+                    &self.exec_context,
+                    call_stack,
+                    Some(datastore),
+                    &bytecode,
+                    None,
+                    &[],
+                    max_gas,
+                )
+                .map(|_| ())
+            }
+            Operation::CallSC {
+                max_gas,
+                coins,
+                target_address,
+                target_function,
+                parameter,
+            } => {
+                let call_stack = vec![
+                    CallItem {
+                        address: creator_address.to_string(),
+                        coins,
+                    },
+                    CallItem {
+                        address: target_address.clone(),
+                        coins,
+                    },
+                ];
+                self.exec_context
+                    .sub(creator_address, coins)
+                    .and_then(|_| self.exec_context.add(&target_address, coins))
+                    .and_then(|_| self.exec_context.get_entry(&target_address))
+                    .and_then(|entry| {
+                        execute_function(
+                            &self.exec_context,
+                            call_stack,
+                            None,
+                            &entry.get_bytecode(),
+                            Some(&target_function),
+                            &parameter,
+                            max_gas,
+                        )
+                    })
+                    .map(|_| ())
+            }
+        };
+        if let Err(err) = result {
+            self.exec_context.restore_snapshot(snapshot)?;
+            let error_event = object!(massa_execution_error: err.to_string()).dump();
+            self.exec_context.push_event(
+                self.exec_context.execution_slot,
+                creator_address.to_string(),
+                error_event,
+                true,
+            )?;
+        }
+        self.exec_context.take_execution_trace()?;
+        Ok(())
+    }
+
+    /// Answers a JSON-RPC request, `tester_advance_slots` advances the given number of slots
+    fn handle(&mut self, method: &str, params: &JsonValue) -> Result<Option<JsonValue>> {
+        let result = match method {
+            "get_addresses" => self.get_addresses(params)?,
+            "get_datastore_entries" => self.get_datastore_entries(params)?,
+            "get_filtered_sc_output_event" => self.get_filtered_sc_output_event(params)?,
+            "execute_read_only_call" => self.execute_read_only_call(params)?,
+            "send_operations" => self.send_operations(params)?,
+            "tester_advance_slots" => {
+                for _ in 0..params[0].as_u64().unwrap_or(1) {
+                    self.advance_slot()?;
+                }
+                self.exec_context.execution_slot.into()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    fn respond(&mut self, mut request: Request) -> Result<()> {
+        // preflight requests of the browsers only need the CORS headers
+        let body = if *request.method() == Method::Options {
+            String::new()
+        } else {
+            let mut content = Vec::new();
+            request.as_reader().read_to_end(&mut content)?;
+            let call = match std::str::from_utf8(&content) {
+                Ok(content) => json::parse(content).map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+            let response = match call {
+                Ok(call) => {
+                    let id = call["id"].clone();
+                    let method = call["method"].as_str().unwrap_or_default().to_string();
+                    match self.handle(&method, &call["params"]) {
+                        Ok(Some(result)) => object!(jsonrpc: "2.0", id: id, result: result),
+                        Ok(None) => object!(
+                            jsonrpc: "2.0",
+                            id: id,
+                            error: { code: -32601, message: format!("unknown method {}", method) }
+                        ),
+                        Err(err) => object!(
+                            jsonrpc: "2.0",
+                            id: id,
+                            error: { code: -32000, message: err.to_string() }
+                        ),
+                    }
+                }
+                Err(message) => object!(
+                    jsonrpc: "2.0",
+                    id: JsonValue::Null,
+                    error: { code: -32700, message: message }
+                ),
+            };
+            response.dump()
+        };
+        let mut response = tiny_http::Response::from_string(body);
+        for (field, value) in [
+            ("Content-Type", "application/json"),
+            ("Access-Control-Allow-Origin", "*"),
+            ("Access-Control-Allow-Headers", "*"),
+        ] {
+            match Header::from_bytes(field, value) {
+                Ok(header) => response.add_header(header),
+                Err(_) => bail!("invalid response header {}", field),
+            }
+        }
+        Ok(request.respond(response)?)
+    }
+}
+
+/// Serves the node JSON-RPC API on the given address, slots are advanced every
+/// `slot_interval` if given and by the `tester_advance_slots` method
+pub(crate) fn serve(
+    exec_context: ExecutionContext,
+    address: &str,
+    slot_interval: Option<Duration>,
+) -> Result<()> {
+    let server = match Server::http(address) {
+        Ok(server) => server,
+        Err(err) => bail!("cannot listen on {}: {}", address, err),
+    };
+    println!("serving the node JSON-RPC API on http://{}", address);
+    let mut node = Node { exec_context };
+    let mut next_slot_time = slot_interval.map(|interval| Instant::now() + interval);
+    loop {
+        // only failing to listen is fatal, the errors of a request or a slot are logged
+        let request = match next_slot_time {
+            Some(time) => server.recv_timeout(time.saturating_duration_since(Instant::now())),
+            None => server.recv().map(Some),
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                eprintln!("receive error: {}", err);
+                None
+            }
+        };
+        if let (Some(time), Some(interval)) = (next_slot_time, slot_interval) {
+            if Instant::now() >= time {
+                if let Err(err) = node.advance_slot() {
+                    let slot = node.exec_context.execution_slot;
+                    eprintln!("slot ({}, {}) error: {}", slot.period, slot.thread, err);
+                }
+                next_slot_time = Some(time + interval);
+            }
+        }
+        if let Some(request) = request {
+            if let Err(err) = node.respond(request) {
+                eprintln!("request error: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{ABI_GAS_COSTS_PATH, WASM_GAS_COSTS_PATH};
+    use massa_sc_runtime::GasCosts;
+
+    #[test]
+    fn decode_call_sc_to_created_address() {
+        let gas_costs =
+            GasCosts::new(ABI_GAS_COSTS_PATH.into(), WASM_GAS_COSTS_PATH.into()).unwrap();
+        let exec_context = ExecutionContext::from_ledger(0, gas_costs, Default::default()).unwrap();
+        let address = exec_context.next_created_address("user_address").unwrap();
+        assert!(address.starts_with("AS"));
+        assert!(Address::from_str(&address).is_ok());
+
+        // version 0 followed by the hash, as serialized after the address type
+        let versioned_hash = bs58::decode(&address[2..])
+            .with_check(None)
+            .into_vec()
+            .unwrap();
+        assert_eq!(versioned_hash.len(), 33);
+        assert_eq!(versioned_hash[0], 0);
+
+        // fee 1, expire period 10, CallSC, max gas 100000, 5 coins, smart contract address
+        let mut content = vec![1, 10, 4, 0xa0, 0x8d, 0x06, 5, 1];
+        content.extend(&versioned_hash);
+        content.push(7);
+        content.extend(b"receive");
+        content.extend([3, 7, 8, 9]);
+        match Operation::decode(&content).unwrap() {
+            (
+                1,
+                Operation::CallSC {
+                    max_gas,
+                    coins,
+                    target_address,
+                    target_function,
+                    parameter,
+                },
+            ) => {
+                assert_eq!(max_gas, 100_000);
+                assert_eq!(coins, 5);
+                assert_eq!(target_address, address);
+                assert_eq!(target_function, "receive");
+                assert_eq!(parameter, vec![7, 8, 9]);
+            }
+            _ => panic!("expected a CallSC operation with a fee of 1"),
+        }
+    }
+}
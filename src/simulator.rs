@@ -0,0 +1,253 @@
+use crate::execution_context::{AsyncMessage, CallItem, Entry, Event, ExecutionContext, Slot};
+use crate::step_manager::{
+    execute_async_messages, execute_function, read_only_call, ReadOnlyOutput,
+};
+use anyhow::Result;
+use json::JsonValue;
+use massa_sc_runtime::{GasCosts, Response};
+use std::{collections::BTreeMap, fs, io::BufReader, path::Path};
+
+/// Output of a successful smart contract execution
+#[derive(Debug)]
+pub struct ExecutionOutput {
+    /// Gas left at the end of the execution
+    pub remaining_gas: u64,
+    /// Addresses created by the execution, in creation order
+    pub created_addresses: Vec<String>,
+    /// Value returned by the executed function
+    pub return_value: Vec<u8>,
+    /// Execution trace, in the format of the trace file
+    pub trace: JsonValue,
+}
+
+/// Output of a successful read-only call
+#[derive(Debug)]
+pub struct ReadOnlyCallOutput {
+    /// Value returned by the called function
+    pub return_value: Vec<u8>,
+    /// Events emitted by the call
+    pub events: Vec<Event>,
+    /// Gas consumed by the call
+    pub gas_used: u64,
+    /// Execution trace, in the format of the trace file
+    pub trace: JsonValue,
+}
+
+/// Simulated chain driven from Rust code
+///
+/// The simulator starts at slot (0, 0) and keeps its state in memory. An execution that fails
+/// returns the error and leaves the state untouched. Asynchronous messages are executed when
+/// the slots are advanced.
+pub struct Simulator {
+    context: ExecutionContext,
+}
+
+impl Simulator {
+    /// Creates a simulator from a ledger, `seed` seeds the random generator of `unsafe_random`
+    pub fn new(ledger: BTreeMap<String, Entry>, gas_costs: GasCosts, seed: u64) -> Result<Self> {
+        Ok(Simulator {
+            context: ExecutionContext::from_ledger(seed, gas_costs, ledger)?,
+        })
+    }
+
+    /// Creates a simulator from a ledger file in the format of `ledger.json` and gas costs files
+    pub fn from_files(
+        ledger_path: &Path,
+        abi_gas_costs_path: &Path,
+        wasm_gas_costs_path: &Path,
+        seed: u64,
+    ) -> Result<Self> {
+        let reader = BufReader::new(fs::File::open(ledger_path)?);
+        let ledger = serde_json::from_reader(reader)?;
+        let gas_costs = GasCosts::new(
+            abi_gas_costs_path.to_path_buf(),
+            wasm_gas_costs_path.to_path_buf(),
+        )?;
+        Simulator::new(ledger, gas_costs, seed)
+    }
+
+    /// Returns the current slot
+    pub fn slot(&self) -> Slot {
+        self.context.execution_slot
+    }
+
+    /// Moves forward by `count` slots, running the asynchronous messages of each of them,
+    /// and returns the trace of the messages
+    pub fn advance_slots(&mut self, count: u64) -> Result<JsonValue> {
+        let mut trace = JsonValue::new_array();
+        for _ in 0..count {
+            let slot = self.context.execution_slot.next();
+            self.context.set_execution_slot(slot)?;
+            execute_async_messages(&self.context, &mut trace)?;
+        }
+        Ok(trace)
+    }
+
+    /// Runs the `main` function of the given bytecode on behalf of `caller`, as an operation
+    /// deploying smart contracts does
+    pub fn deploy(&mut self, caller: &str, bytecode: &[u8], gas: u64) -> Result<ExecutionOutput> {
+        self.execute(caller, bytecode, None, &[], gas)
+    }
+
+    /// Runs a function of the given bytecode on behalf of `caller`, `main` if none is given
+    pub fn execute(
+        &mut self,
+        caller: &str,
+        bytecode: &[u8],
+        function: Option<&str>,
+        parameter: &[u8],
+        gas: u64,
+    ) -> Result<ExecutionOutput> {
+        let call_stack = vec![CallItem {
+            address: caller.to_string(),
+            coins: 0,
+        }];
+        self.run(
+            call_stack,
+            Some(Default::default()),
+            bytecode,
+            function,
+            parameter,
+            gas,
+        )
+    }
+
+    /// Calls a function of the smart contract at `address` on behalf of `caller`, transferring
+    /// `coins` from the caller to the smart contract as a node does
+    pub fn call(
+        &mut self,
+        caller: &str,
+        address: &str,
+        function: &str,
+        parameter: &[u8],
+        coins: u64,
+        gas: u64,
+    ) -> Result<ExecutionOutput> {
+        let bytecode = self.context.get_entry(address)?.get_bytecode();
+        let call_stack = vec![
+            CallItem {
+                address: caller.to_string(),
+                coins,
+            },
+            CallItem {
+                address: address.to_string(),
+                coins,
+            },
+        ];
+        // the transfer is rolled back with the changes of a failed execution
+        let snapshot = self.context.take_snapshot()?;
+        let result = self
+            .context
+            .sub(caller, coins)
+            .and_then(|_| self.context.add(address, coins))
+            .and_then(|_| self.run(call_stack, None, &bytecode, Some(function), parameter, gas));
+        if result.is_err() {
+            self.context.restore_snapshot(snapshot)?;
+        }
+        result
+    }
+
+    /// Calls a function of the smart contract at `address` on behalf of `caller` on a copy of
+    /// the state, none of its changes are kept, `coins` are only seen by the called function
+    pub fn read_only_call(
+        &self,
+        caller: &str,
+        address: &str,
+        function: &str,
+        parameter: &[u8],
+        coins: u64,
+        gas: u64,
+    ) -> Result<ReadOnlyCallOutput> {
+        let call_stack = vec![
+            CallItem {
+                address: caller.to_string(),
+                coins,
+            },
+            CallItem {
+                address: address.to_string(),
+                coins,
+            },
+        ];
+        let ReadOnlyOutput {
+            result,
+            events,
+            trace,
+        } = read_only_call(&self.context, call_stack, address, function, parameter, gas)?;
+        let Response {
+            ret, remaining_gas, ..
+        } = result?;
+        Ok(ReadOnlyCallOutput {
+            return_value: ret,
+            events,
+            gas_used: gas.saturating_sub(remaining_gas),
+            trace,
+        })
+    }
+
+    fn run(
+        &mut self,
+        call_stack: Vec<CallItem>,
+        op_datastore: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+        bytecode: &[u8],
+        function: Option<&str>,
+        parameter: &[u8],
+        gas: u64,
+    ) -> Result<ExecutionOutput> {
+        let result = execute_function(
+            &self.context,
+            call_stack,
+            op_datastore,
+            bytecode,
+            function,
+            parameter,
+            gas,
+        );
+        let trace = self.context.take_execution_trace()?;
+        let Response {
+            ret, remaining_gas, ..
+        } = result?;
+        self.context.last_remaining_gas = Some(remaining_gas);
+        Ok(ExecutionOutput {
+            remaining_gas,
+            created_addresses: self.context.owned_to_vec()?,
+            return_value: ret,
+            trace,
+        })
+    }
+
+    /// Returns the events emitted from `start` included to `end` excluded,
+    /// a missing bound is unbounded
+    pub fn events(&self, start: Option<Slot>, end: Option<Slot>) -> Result<Vec<Event>> {
+        self.context.get_events_in(start, end)
+    }
+
+    /// Returns the pending asynchronous messages whose validity starts from `start` included
+    /// to `end` excluded, a missing bound is unbounded
+    pub fn async_messages(
+        &self,
+        start: Option<Slot>,
+        end: Option<Slot>,
+    ) -> Result<Vec<AsyncMessage>> {
+        self.context.get_async_messages_in(start, end)
+    }
+
+    /// Returns the ledger entry of an address
+    pub fn entry(&self, address: &str) -> Result<Entry> {
+        self.context.get_entry(address)
+    }
+
+    /// Returns the balance of an address
+    pub fn balance(&self, address: &str) -> Result<u64> {
+        Ok(self.context.get_entry(address)?.balance)
+    }
+
+    /// Returns a datastore entry of an address, None if the key does not exist
+    pub fn datastore_entry(&self, address: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.context.get_entry(address)?.datastore.get(key).cloned())
+    }
+
+    /// Replaces the ledger entry of an address, creating it if needed
+    pub fn set_entry(&mut self, address: &str, entry: Entry) -> Result<()> {
+        self.context.create_new_entry(address.to_string(), entry)
+    }
+}
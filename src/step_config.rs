@@ -1,10 +1,212 @@
-use crate::execution_context::{CallItem, Slot};
-use serde::Deserialize;
+use crate::args::{serialize_args, Arg, ReturnType};
+use crate::execution_context::{CallItem, ExecutionContext, Slot};
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer,
+};
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt, fs,
+    path::Path,
 };
 
+/// Bytes given as a list of integers, a list of typed values serialized as the massa-as-sdk
+/// `Args` do, an hex or base64 string, the path of a file to read or the name of a variable
+/// whose UTF-8 value is used
+#[derive(Debug)]
+pub(crate) enum BytesInput {
+    Raw(Vec<u8>),
+    Args(Vec<Arg>),
+    Hex { hex: String },
+    Base64 { base64: String },
+    String { string: String },
+    File { path: String },
+    Variable { variable: String },
+}
+
+/// Object forms of the bytes inputs
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BytesObject {
+    Hex(String),
+    Base64(String),
+    String(String),
+    Path(String),
+    Variable(String),
+}
+
+/// Item of a list of bytes, a byte or a typed value
+enum BytesItem {
+    Byte(u8),
+    Arg(Arg),
+}
+
+struct BytesItemVisitor;
+
+impl<'de> Visitor<'de> for BytesItemVisitor {
+    type Value = BytesItem;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte or a typed value")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        match u8::try_from(value) {
+            Ok(byte) => Ok(BytesItem::Byte(byte)),
+            Err(_) => Err(E::custom(format!("{} is not a byte", value))),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        Ok(BytesItem::Arg(Arg::deserialize(
+            MapAccessDeserializer::new(map),
+        )?))
+    }
+}
+
+impl<'de> Deserialize<'de> for BytesItem {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BytesItemVisitor)
+    }
+}
+
+/// Deserializes the bytes inputs by hand so that an invalid typed value or object is reported
+/// as such, rather than as matching none of the input forms
+struct BytesInputVisitor;
+
+impl<'de> Visitor<'de> for BytesInputVisitor {
+    type Value = BytesInput;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(
+            "a list of bytes, a list of typed values or a hex, base64, string, path or variable \
+             object",
+        )
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::new();
+        let mut args = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            match item {
+                BytesItem::Byte(byte) => bytes.push(byte),
+                BytesItem::Arg(arg) => args.push(arg),
+            }
+        }
+        match (bytes.is_empty(), args.is_empty()) {
+            (_, true) => Ok(BytesInput::Raw(bytes)),
+            (true, false) => Ok(BytesInput::Args(args)),
+            (false, false) => Err(de::Error::custom(
+                "a list cannot mix bytes and typed values",
+            )),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let input = match BytesObject::deserialize(MapAccessDeserializer::new(map))? {
+            BytesObject::Hex(hex) => BytesInput::Hex { hex },
+            BytesObject::Base64(base64) => BytesInput::Base64 { base64 },
+            BytesObject::String(string) => BytesInput::String { string },
+            BytesObject::Path(path) => BytesInput::File { path },
+            BytesObject::Variable(variable) => BytesInput::Variable { variable },
+        };
+        Ok(input)
+    }
+}
+
+impl<'de> Deserialize<'de> for BytesInput {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BytesInputVisitor)
+    }
+}
+
+impl BytesInput {
+    pub(crate) fn to_bytes(&self, exec_context: &ExecutionContext) -> Result<Vec<u8>> {
+        match self {
+            BytesInput::Raw(bytes) => Ok(bytes.clone()),
+            BytesInput::Args(args) => serialize_args(args, exec_context),
+            BytesInput::Hex { hex } => match hex::decode(hex.trim_start_matches("0x")) {
+                Ok(bytes) => Ok(bytes),
+                Err(err) => bail!("{} is not a valid hex string: {}", hex, err),
+            },
+            BytesInput::Base64 { base64 } => match general_purpose::STANDARD.decode(base64) {
+                Ok(bytes) => Ok(bytes),
+                Err(err) => bail!("{} is not a valid base64 string: {}", base64, err),
+            },
+            BytesInput::String { string } => Ok(string.as_bytes().to_vec()),
+            BytesInput::File { path } => {
+                if !Path::new(path).is_file() {
+                    bail!("{} isn't a file", path)
+                }
+                Ok(fs::read(path)?)
+            }
+            BytesInput::Variable { variable } => Ok(exec_context
+                .resolve(&format!("${}", variable))?
+                .into_bytes()),
+        }
+    }
+}
+
+/// Datastore entry of an operation or a ledger entry
+#[derive(Debug, Deserialize)]
+pub(crate) struct DatastoreEntry {
+    pub key: BytesInput,
+    pub value: BytesInput,
+}
+
+/// Datastore given as a map of base64 keys to values, as in the ledger file,
+/// or as a list of entries
+#[derive(Debug)]
+pub(crate) enum DatastoreConfig {
+    Base64(BTreeMap<String, Vec<u8>>),
+    Entries(Vec<DatastoreEntry>),
+}
+
+struct DatastoreConfigVisitor;
+
+impl<'de> Visitor<'de> for DatastoreConfigVisitor {
+    type Value = DatastoreConfig;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of base64 keys to values or a list of key and value entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        Ok(DatastoreConfig::Entries(Vec::deserialize(
+            SeqAccessDeserializer::new(seq),
+        )?))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        Ok(DatastoreConfig::Base64(BTreeMap::deserialize(
+            MapAccessDeserializer::new(map),
+        )?))
+    }
+}
+
+impl<'de> Deserialize<'de> for DatastoreConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DatastoreConfigVisitor)
+    }
+}
+
+/// Ledger change that makes a filtered message executable
+#[derive(Debug, Deserialize)]
+pub(crate) struct TriggerConfig {
+    /// Address whose modification triggers the message
+    pub address: String,
+    /// Datastore key of the address whose modification triggers the message,
+    /// any modification of the address does if omitted
+    pub datastore_key: Option<BytesInput>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub(crate) enum StepConfig {
@@ -15,11 +217,19 @@ pub(crate) enum StepConfig {
         /// Function of the smart contract to be tested, default is 'main'
         function: Option<String>,
         /// Parameter of the given function
-        parameter: Option<Vec<u8>>,
+        parameter: Option<BytesInput>,
         /// Gas for execution
         gas: u64,
         /// ExecuteSC callstack
         call_stack: VecDeque<CallItem>,
+        /// Variable names bound to the addresses created by the execution, in creation order,
+        /// use `$name` in the following steps to refer to them
+        created_addresses: Option<Vec<String>>,
+        /// Operation datastore, readable with the get_op_keys, has_op_key and get_op_data ABIs
+        datastore: Option<Vec<DatastoreEntry>>,
+        /// Type the return value is decoded as in the trace: a type name or a list of type
+        /// names for values serialized with Args
+        return_type: Option<ReturnType>,
     },
     #[serde(rename = "call_sc")]
     CallSC {
@@ -28,11 +238,33 @@ pub(crate) enum StepConfig {
         /// Function of the smart contract to be tested, default is 'main'
         function: Option<String>,
         /// Parameter of the given function
-        parameter: Option<Vec<u8>>,
+        parameter: Option<BytesInput>,
         /// Gas for execution
         gas: u64,
         /// CallSC callstack
         call_stack: VecDeque<CallItem>,
+        /// Variable names bound to the addresses created by the execution, in creation order,
+        /// use `$name` in the following steps to refer to them
+        created_addresses: Option<Vec<String>>,
+        /// Type the return value is decoded as in the trace: a type name or a list of type
+        /// names for values serialized with Args
+        return_type: Option<ReturnType>,
+    },
+    /// Calls a function on a copy of the state, none of its changes are kept
+    ReadOnlyCall {
+        /// Address of the smart contract
+        address: String,
+        /// Function of the smart contract to be called, default is 'main'
+        function: Option<String>,
+        /// Parameter of the given function
+        parameter: Option<BytesInput>,
+        /// Gas for execution
+        gas: u64,
+        /// ReadOnlyCall callstack
+        call_stack: VecDeque<CallItem>,
+        /// Type the return value is decoded as in the trace: a type name or a list of type
+        /// names for values serialized with Args
+        return_type: Option<ReturnType>,
     },
     ReadEvents {
         /// Start slot
@@ -51,8 +283,9 @@ pub(crate) enum StepConfig {
         balance: Option<u64>,
         /// Entry bytecode
         bytecode: Option<String>,
-        /// Entry datastore
-        datastore: Option<BTreeMap<String, Vec<u8>>>,
+        /// Entry datastore, a map of base64 keys to values as in the ledger file or a list of
+        /// key and value entries
+        datastore: Option<DatastoreConfig>,
     },
     ReadAsyncMessages {
         /// Start slot
@@ -67,14 +300,86 @@ pub(crate) enum StepConfig {
         target_address: String,
         /// Smart contract function
         target_handler: String,
-        /// Slot at which the message will be executed
+        /// First slot at which the message can be executed
         execution_slot: Slot,
+        /// Slot at which the message expires, it never does if omitted
+        validity_end: Option<Slot>,
         /// Execution gas
         gas: u64,
+        /// Price of a unit of gas, messages with a higher price are executed first, default is '0'
+        gas_price: Option<u64>,
         /// Provided coins
         coins: u64,
         /// Smart contract function parameter
-        data: Vec<u8>,
+        data: BytesInput,
+        /// Ledger change the message waits for before being executable, if any
+        trigger: Option<TriggerConfig>,
+    },
+    SignData {
+        /// Name of the keypair to sign with, as declared in the execution config
+        keypair: String,
+        /// Signed data, it is hashed before being signed as done by the signature_verify ABI
+        data: BytesInput,
+        /// Variable the signature is saved to
+        variable: String,
+    },
+    SnapshotState {
+        /// Name the snapshot is saved under, an existing snapshot with the same name is replaced
+        name: String,
+    },
+    RestoreState {
+        /// Name of a snapshot taken by a previous snapshot_state step
+        name: String,
+    },
+    AssertBalance {
+        /// Entry address
+        address: String,
+        /// Expected balance
+        balance: u64,
+    },
+    AssertDatastoreEntry {
+        /// Entry address
+        address: String,
+        /// Datastore key
+        key: BytesInput,
+        /// Expected value, only the presence of the key is checked if omitted
+        value: Option<BytesInput>,
+    },
+    AssertEventEmitted {
+        /// Start slot
+        start: Option<Slot>,
+        /// End slot
+        end: Option<Slot>,
+        /// Expected event emitter, any emitter matches if omitted
+        sender_address: Option<String>,
+        /// Expected event data
+        data: String,
+    },
+    AssertAsyncMessagePending {
+        /// Start slot
+        start: Option<Slot>,
+        /// End slot
+        end: Option<Slot>,
+        /// Expected message sender, any sender matches if omitted
+        sender_address: Option<String>,
+        /// Expected message target, any target matches if omitted
+        target_address: Option<String>,
+        /// Expected message handler, any handler matches if omitted
+        target_handler: Option<String>,
+    },
+    AssertRemainingGasBetween {
+        /// Minimum remaining gas of the last execute_sc or call_sc step, inclusive
+        min: u64,
+        /// Maximum remaining gas of the last execute_sc or call_sc step, inclusive
+        max: u64,
+    },
+    AssertReturnValue {
+        /// Expected raw value returned by the last execute_sc, call_sc or read_only_call step
+        raw: Option<BytesInput>,
+        /// Type the return value is decoded as before being compared to `value`
+        return_type: Option<ReturnType>,
+        /// Expected decoded return value
+        value: Option<serde_json::Value>,
     },
 }
 
@@ -109,3 +414,95 @@ impl PartialEq for SlotExecutionSteps {
 }
 
 impl Eq for SlotExecutionSteps {}
+
+/// Execution config, either a list of slot executions or an object with global settings
+#[derive(Debug, Default)]
+pub(crate) struct ExecutionConfig {
+    /// Seed of the random generator, a random seed is used if omitted
+    pub seed: Option<u64>,
+    /// Names of the test keypairs to generate from the seed
+    pub keypairs: Vec<String>,
+    /// Files read and written by the run, overridden by the command line options
+    pub paths: PathsConfig,
+    /// Steps to execute, grouped by slot
+    pub executions: BTreeSet<SlotExecutionSteps>,
+}
+
+/// Files read and written by the run, the defaults of `constants.rs` are used if omitted
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PathsConfig {
+    /// Ledger loaded at start
+    pub ledger_input: Option<String>,
+    /// Ledger written at the end of each step
+    pub ledger_output: Option<String>,
+    /// Chain state loaded at start
+    pub state_input: Option<String>,
+    /// Chain state written at the end of each step
+    pub state_output: Option<String>,
+    /// Execution trace written at the end of the run
+    pub trace_output: Option<String>,
+    pub abi_gas_costs: Option<String>,
+    pub wasm_gas_costs: Option<String>,
+    /// Directory the compiled modules are saved to and reused from by the next runs
+    pub module_cache: Option<String>,
+    /// Start from an empty ledger and chain state instead of loading them
+    #[serde(default)]
+    pub empty_ledger: bool,
+    /// Whether the ledger and chain state are written back, default is true
+    pub write_ledger: Option<bool>,
+}
+
+/// Object format of the execution config
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExecutionConfigObject {
+    seed: Option<u64>,
+    #[serde(default)]
+    keypairs: Vec<String>,
+    #[serde(default)]
+    paths: PathsConfig,
+    executions: BTreeSet<SlotExecutionSteps>,
+}
+
+struct ExecutionConfigVisitor;
+
+impl<'de> Visitor<'de> for ExecutionConfigVisitor {
+    type Value = ExecutionConfig;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of slot executions or an execution config object")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut executions = BTreeSet::new();
+        while let Some(slot_execution_steps) = seq.next_element()? {
+            executions.insert(slot_execution_steps);
+        }
+        Ok(ExecutionConfig {
+            executions,
+            ..Default::default()
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let ExecutionConfigObject {
+            seed,
+            keypairs,
+            paths,
+            executions,
+        } = ExecutionConfigObject::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(ExecutionConfig {
+            seed,
+            keypairs,
+            paths,
+            executions,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ExecutionConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ExecutionConfigVisitor)
+    }
+}
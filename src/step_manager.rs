@@ -1,15 +1,200 @@
-use crate::execution_context::{AsyncMessage, CallItem, Entry, ExecutionContext};
-use crate::step_config::StepConfig;
+use crate::args::return_value_to_json;
+use crate::assertion;
+use crate::execution_context::{
+    AsyncMessage, AsyncMessageTrigger, CallItem, Entry, Event, ExecutionContext,
+    OperationDatastore, Slot,
+};
+use crate::step_config::{DatastoreConfig, DatastoreEntry, StepConfig, TriggerConfig};
 use anyhow::{bail, Result};
+use base64::{engine::general_purpose, Engine as _};
 use json::{object, JsonValue};
-use massa_sc_runtime::{run_function, run_main, Compiler, Response, RuntimeModule};
-use std::{fs, path::Path};
+use massa_sc_runtime::{run_function, run_main, Response};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::Path,
+};
 
-pub(crate) fn execute_step(
-    exec_context: &mut ExecutionContext,
-    config_step: StepConfig,
-) -> Result<JsonValue> {
-    let mut trace = JsonValue::new_array();
+pub(crate) struct StepOutput {
+    /// Execution trace of the step
+    pub trace: JsonValue,
+    /// Failure message if the step is an assertion that did not hold or an execution that failed
+    pub failure: Option<String>,
+    /// Gas consumed by the step if it executes a smart contract
+    pub gas_used: Option<u64>,
+}
+
+/// Pushes the result of an assertion in the trace and returns its failure message if any
+fn push_assertion(
+    trace: &mut JsonValue,
+    assertion: &str,
+    result: Result<()>,
+) -> Result<Option<String>> {
+    let failure = result.err().map(|err| err.to_string());
+    let json = object!(
+        [assertion]: {
+            success: failure.is_none(),
+            error: failure.clone(),
+        }
+    );
+    trace.push(json)?;
+    Ok(failure)
+}
+
+/// Compiles the given bytecode and runs one of its functions, `main` if none is given
+fn run_module(
+    exec_context: &ExecutionContext,
+    bytecode: &[u8],
+    function: Option<&str>,
+    parameter: &[u8],
+    gas: u64,
+) -> Result<Response> {
+    let module = exec_context.load_module(bytecode, gas)?;
+    match function {
+        Some(function) => run_function(
+            exec_context,
+            module,
+            function,
+            parameter,
+            gas,
+            exec_context.gas_costs.clone(),
+        ),
+        None => run_main(exec_context, module, gas, exec_context.gas_costs.clone()),
+    }
+}
+
+/// Resolves an optional address that may refer to a variable
+fn resolve_opt(exec_context: &ExecutionContext, address: Option<String>) -> Result<Option<String>> {
+    address
+        .map(|address| exec_context.resolve(&address))
+        .transpose()
+}
+
+/// Resolves the addresses of a call stack that may refer to variables
+fn resolve_call_stack(
+    exec_context: &ExecutionContext,
+    call_stack: VecDeque<CallItem>,
+) -> Result<Vec<CallItem>> {
+    call_stack
+        .into_iter()
+        .map(|call_item| {
+            Ok(CallItem {
+                address: exec_context.resolve(&call_item.address)?,
+                ..call_item
+            })
+        })
+        .collect()
+}
+
+/// Binds the addresses created by the last execution to the given variable names
+fn bind_created_addresses(
+    exec_context: &ExecutionContext,
+    names: Option<Vec<String>>,
+) -> Result<()> {
+    let names = names.unwrap_or_default();
+    let created = exec_context.owned_to_vec()?;
+    if names.len() > created.len() {
+        bail!(
+            "{} addresses were created, cannot bind {} variables",
+            created.len(),
+            names.len()
+        )
+    }
+    for (name, address) in names.iter().zip(created) {
+        exec_context.set_variable(name, &address)?;
+    }
+    Ok(())
+}
+
+/// Runs a function of the given bytecode with the given call stack and operation datastore,
+/// rolling back its changes on failure
+pub(crate) fn execute_function(
+    exec_context: &ExecutionContext,
+    call_stack: Vec<CallItem>,
+    op_datastore: Option<OperationDatastore>,
+    bytecode: &[u8],
+    function: Option<&str>,
+    parameter: &[u8],
+    gas: u64,
+) -> Result<Response> {
+    exec_context.reset_addresses()?;
+    for call_item in call_stack {
+        exec_context.call_stack_push(call_item)?;
+    }
+    exec_context.set_op_datastore(op_datastore)?;
+    let snapshot = exec_context.take_snapshot()?;
+    let result = run_module(exec_context, bytecode, function, parameter, gas);
+    if result.is_err() {
+        exec_context.restore_snapshot(snapshot)?;
+    }
+    result
+}
+
+/// Output of a read-only call
+pub(crate) struct ReadOnlyOutput {
+    pub result: Result<Response>,
+    /// Events emitted by the call
+    pub events: Vec<Event>,
+    /// Execution trace of the call
+    pub trace: JsonValue,
+}
+
+/// Calls a function of a smart contract on a copy of the context, so that none of its changes
+/// are kept
+pub(crate) fn read_only_call(
+    exec_context: &ExecutionContext,
+    call_stack: Vec<CallItem>,
+    address: &str,
+    function: &str,
+    parameter: &[u8],
+    gas: u64,
+) -> Result<ReadOnlyOutput> {
+    let fork = exec_context.fork()?;
+    let slot = fork.execution_slot;
+    let emitted = fork.get_events_in(Some(slot), Some(slot.next()))?.len();
+    let result = fork.get_entry(address).and_then(|entry| {
+        execute_function(
+            &fork,
+            call_stack,
+            None,
+            &entry.get_bytecode(),
+            Some(function),
+            parameter,
+            gas,
+        )
+    });
+    let events = fork
+        .get_events_in(Some(slot), Some(slot.next()))?
+        .into_iter()
+        .skip(emitted)
+        .collect();
+    Ok(ReadOnlyOutput {
+        result,
+        events,
+        trace: fork.take_execution_trace()?,
+    })
+}
+
+/// Drops the expired asynchronous messages and runs the executable ones, pushing their traces
+pub(crate) fn execute_async_messages(
+    exec_context: &ExecutionContext,
+    trace: &mut JsonValue,
+) -> Result<()> {
+    // drop the expired asynchronous messages
+    let (expired_messages, messages_to_execute) = exec_context.take_async_messages()?;
+    for message in expired_messages {
+        // refund the coins to the sender, the fee is not refunded
+        let refunded_coins = message.coins;
+        exec_context.add(&message.sender_address, refunded_coins)?;
+        let message: JsonValue = message.into();
+        let json = object!(
+            expire_async_message: {
+                refunded_coins: refunded_coins,
+                message: message,
+            }
+        );
+        trace.push(json)?;
+    }
 
     // run the asynchronous messages
     for AsyncMessage {
@@ -19,12 +204,14 @@ pub(crate) fn execute_step(
         gas,
         coins,
         data,
-    } in exec_context.get_async_messages_to_execute()?
+        ..
+    } in messages_to_execute
     {
         // set the call stack
         exec_context.reset_addresses()?;
+        exec_context.set_op_datastore(None)?;
         exec_context.call_stack_push(CallItem {
-            address: sender_address,
+            address: sender_address.clone(),
             coins,
         })?;
         exec_context.call_stack_push(CallItem {
@@ -32,34 +219,72 @@ pub(crate) fn execute_step(
             coins,
         })?;
 
-        // read the bytecode
-        let module = RuntimeModule::new(
-            &exec_context.get_entry(&target_address)?.get_bytecode(),
-            gas,
-            exec_context.gas_costs.clone(),
-            Compiler::CL,
-        )?;
-
-        // execute the function
-        let Response { remaining_gas, .. } = run_function(
-            exec_context,
-            module,
-            &target_handler,
-            &data,
-            gas,
-            exec_context.gas_costs.clone(),
-        )?;
+        // credit the coins to the target and execute the function,
+        // rolling back its changes on failure
+        let snapshot = exec_context.take_snapshot()?;
+        let result = exec_context
+            .add(&target_address, coins)
+            .and_then(|_| exec_context.get_entry(&target_address))
+            .and_then(|entry| {
+                run_module(
+                    exec_context,
+                    &entry.get_bytecode(),
+                    Some(target_handler.as_str()),
+                    &data,
+                    gas,
+                )
+            });
 
         // push the message trace
-        let json = object!(
-            execute_async_message: {
-                name: target_handler,
-                remaining_gas: remaining_gas,
-                output: exec_context.take_execution_trace()?,
+        let json = match result {
+            Ok(Response {
+                ret, remaining_gas, ..
+            }) => object!(
+                execute_async_message: {
+                    name: target_handler,
+                    credited_coins: coins,
+                    consumed_gas: gas.saturating_sub(remaining_gas),
+                    remaining_gas: remaining_gas,
+                    return_value: return_value_to_json(&ret, None),
+                    output: exec_context.take_execution_trace()?,
+                }
+            ),
+            Err(err) => {
+                // refund the coins to the sender and emit an error event as the node does,
+                // the whole gas of a failed message is consumed
+                exec_context.restore_snapshot(snapshot)?;
+                exec_context.add(&sender_address, coins)?;
+                let error_event = object!(massa_execution_error: err.to_string()).dump();
+                exec_context.push_event(
+                    exec_context.execution_slot,
+                    target_address,
+                    error_event,
+                    true,
+                )?;
+                object!(
+                    execute_async_message: {
+                        name: target_handler,
+                        error: err.to_string(),
+                        refunded_coins: coins,
+                        consumed_gas: gas,
+                        output: exec_context.take_execution_trace()?,
+                    }
+                )
             }
-        );
+        };
         trace.push(json)?;
     }
+    Ok(())
+}
+
+pub(crate) fn execute_step(
+    exec_context: &mut ExecutionContext,
+    config_step: StepConfig,
+) -> Result<StepOutput> {
+    let mut trace = JsonValue::new_array();
+    let mut failure = None;
+    let mut gas_used = None;
+    execute_async_messages(exec_context, &mut trace)?;
 
     // match the config step
     match config_step {
@@ -69,11 +294,15 @@ pub(crate) fn execute_step(
             parameter,
             gas,
             call_stack,
+            created_addresses,
+            datastore,
+            return_type,
         } => {
             // init the context
-            exec_context.reset_addresses()?;
-            for call_item in call_stack {
-                exec_context.call_stack_push(call_item)?;
+            let call_stack = resolve_call_stack(exec_context, call_stack)?;
+            let mut op_datastore = BTreeMap::new();
+            for DatastoreEntry { key, value } in datastore.unwrap_or_default() {
+                op_datastore.insert(key.to_bytes(exec_context)?, value.to_bytes(exec_context)?);
             }
 
             // read the wasm file
@@ -86,37 +315,56 @@ pub(crate) fn execute_step(
                 bail!("{} extension should be .wasm", path)
             }
             let bytecode = fs::read(sc_path)?;
-            let module =
-                RuntimeModule::new(&bytecode, gas, exec_context.gas_costs.clone(), Compiler::CL)?;
-
-            // execute the function
-            let (Response { remaining_gas, .. }, function_name) = if let Some(function) = function {
-                (
-                    run_function(
-                        exec_context,
-                        module,
-                        &function,
-                        &parameter.unwrap_or_default(),
-                        gas,
-                        exec_context.gas_costs.clone(),
-                    )?,
-                    function,
-                )
-            } else {
-                (
-                    run_main(exec_context, module, gas, exec_context.gas_costs.clone())?,
-                    "main".to_string(),
-                )
+
+            // execute the function, rolling back its changes on failure
+            let parameter = match parameter {
+                Some(parameter) => parameter.to_bytes(exec_context)?,
+                None => Vec::new(),
             };
+            let result = execute_function(
+                exec_context,
+                call_stack,
+                Some(op_datastore),
+                &bytecode,
+                function.as_deref(),
+                &parameter,
+                gas,
+            );
+            let function_name = function.unwrap_or_else(|| "main".to_string());
 
             // push the function trace
-            let json = object!(
-                execute_sc: {
-                    name: function_name,
-                    remaining_gas: remaining_gas,
-                    output: exec_context.take_execution_trace()?,
+            let json = match result {
+                Ok(Response {
+                    ret, remaining_gas, ..
+                }) => {
+                    exec_context.last_remaining_gas = Some(remaining_gas);
+                    gas_used = Some(gas.saturating_sub(remaining_gas));
+                    bind_created_addresses(exec_context, created_addresses)?;
+                    let return_value = return_value_to_json(&ret, return_type.as_ref());
+                    exec_context.last_return_value = Some(ret);
+                    object!(
+                        execute_sc: {
+                            name: function_name,
+                            remaining_gas: remaining_gas,
+                            return_value: return_value,
+                            output: exec_context.take_execution_trace()?,
+                        }
+                    )
                 }
-            );
+                Err(err) => {
+                    gas_used = Some(gas);
+                    exec_context.last_remaining_gas = None;
+                    exec_context.last_return_value = None;
+                    failure = Some(err.to_string());
+                    object!(
+                        execute_sc: {
+                            name: function_name,
+                            error: err.to_string(),
+                            output: exec_context.take_execution_trace()?,
+                        }
+                    )
+                }
+            };
             trace.push(json)?;
         }
         StepConfig::CallSC {
@@ -125,49 +373,124 @@ pub(crate) fn execute_step(
             parameter,
             gas,
             call_stack,
+            created_addresses,
+            return_type,
         } => {
             // init the context
-            exec_context.reset_addresses()?;
-            for call_item in call_stack {
-                exec_context.call_stack_push(call_item)?;
-            }
+            let address = exec_context.resolve(&address)?;
+            let call_stack = resolve_call_stack(exec_context, call_stack)?;
 
-            // read the bytecode
-            let module = RuntimeModule::new(
-                &exec_context.get_entry(&address)?.get_bytecode(),
-                gas,
-                exec_context.gas_costs.clone(),
-                Compiler::CL,
-            )?;
-
-            // execute the function
-            let (Response { remaining_gas, .. }, function_name) = if let Some(function) = function {
-                (
-                    run_function(
-                        exec_context,
-                        module,
-                        &function,
-                        &parameter.unwrap_or_default(),
-                        gas,
-                        exec_context.gas_costs.clone(),
-                    )?,
-                    function,
-                )
-            } else {
-                (
-                    run_main(exec_context, module, gas, exec_context.gas_costs.clone())?,
-                    "main".to_string(),
-                )
+            // execute the function, rolling back its changes on failure
+            let parameter = match parameter {
+                Some(parameter) => parameter.to_bytes(exec_context)?,
+                None => Vec::new(),
             };
+            let result = exec_context.get_entry(&address).and_then(|entry| {
+                execute_function(
+                    exec_context,
+                    call_stack,
+                    None,
+                    &entry.get_bytecode(),
+                    function.as_deref(),
+                    &parameter,
+                    gas,
+                )
+            });
+            let function_name = function.unwrap_or_else(|| "main".to_string());
 
             // push the function trace
-            let json = object!(
-                call_sc: {
-                    name: function_name,
-                    remaining_gas: remaining_gas,
-                    output: exec_context.take_execution_trace()?,
+            let json = match result {
+                Ok(Response {
+                    ret, remaining_gas, ..
+                }) => {
+                    exec_context.last_remaining_gas = Some(remaining_gas);
+                    gas_used = Some(gas.saturating_sub(remaining_gas));
+                    bind_created_addresses(exec_context, created_addresses)?;
+                    let return_value = return_value_to_json(&ret, return_type.as_ref());
+                    exec_context.last_return_value = Some(ret);
+                    object!(
+                        call_sc: {
+                            name: function_name,
+                            remaining_gas: remaining_gas,
+                            return_value: return_value,
+                            output: exec_context.take_execution_trace()?,
+                        }
+                    )
                 }
-            );
+                Err(err) => {
+                    gas_used = Some(gas);
+                    exec_context.last_remaining_gas = None;
+                    exec_context.last_return_value = None;
+                    failure = Some(err.to_string());
+                    object!(
+                        call_sc: {
+                            name: function_name,
+                            error: err.to_string(),
+                            output: exec_context.take_execution_trace()?,
+                        }
+                    )
+                }
+            };
+            trace.push(json)?;
+        }
+        StepConfig::ReadOnlyCall {
+            address,
+            function,
+            parameter,
+            gas,
+            call_stack,
+            return_type,
+        } => {
+            let address = exec_context.resolve(&address)?;
+            let call_stack = resolve_call_stack(exec_context, call_stack)?;
+            let parameter = match parameter {
+                Some(parameter) => parameter.to_bytes(exec_context)?,
+                None => Vec::new(),
+            };
+            let function_name = function.unwrap_or_else(|| "main".to_string());
+            let ReadOnlyOutput {
+                result,
+                events,
+                trace: output,
+            } = read_only_call(
+                exec_context,
+                call_stack,
+                &address,
+                &function_name,
+                &parameter,
+                gas,
+            )?;
+            let json = match result {
+                Ok(Response {
+                    ret, remaining_gas, ..
+                }) => {
+                    gas_used = Some(gas.saturating_sub(remaining_gas));
+                    let return_value = return_value_to_json(&ret, return_type.as_ref());
+                    exec_context.last_return_value = Some(ret);
+                    object!(
+                        read_only_call: {
+                            name: function_name,
+                            return_value: return_value,
+                            events: events,
+                            remaining_gas: remaining_gas,
+                            output: output,
+                        }
+                    )
+                }
+                Err(err) => {
+                    gas_used = Some(gas);
+                    exec_context.last_return_value = None;
+                    failure = Some(err.to_string());
+                    object!(
+                        read_only_call: {
+                            name: function_name,
+                            error: err.to_string(),
+                            events: events,
+                            output: output,
+                        }
+                    )
+                }
+            };
             trace.push(json)?;
         }
         StepConfig::ReadEvents { start, end } => {
@@ -176,7 +499,7 @@ pub(crate) fn execute_step(
             trace.push(json)?;
         }
         StepConfig::ReadLedgerEntry { address } => {
-            let entry = exec_context.get_entry(&address)?;
+            let entry = exec_context.get_entry(&exec_context.resolve(&address)?)?;
             let json = object!(read_ledger_entry: JsonValue::from(Some(entry)));
             trace.push(json)?;
         }
@@ -191,12 +514,31 @@ pub(crate) fn execute_step(
                 None => None,
             };
 
+            let mut datastore_ = BTreeMap::new();
+            match datastore {
+                Some(DatastoreConfig::Base64(datastore)) => {
+                    for (key, value) in datastore {
+                        match general_purpose::STANDARD.decode(&key) {
+                            Ok(key) => datastore_.insert(key, value),
+                            Err(err) => bail!("datastore key {} is not valid base64: {}", key, err),
+                        };
+                    }
+                }
+                Some(DatastoreConfig::Entries(entries)) => {
+                    for DatastoreEntry { key, value } in entries {
+                        datastore_
+                            .insert(key.to_bytes(exec_context)?, value.to_bytes(exec_context)?);
+                    }
+                }
+                None => (),
+            }
+
             exec_context.create_new_entry(
-                address,
+                exec_context.resolve(&address)?,
                 Entry {
                     balance: balance.unwrap_or_default(),
                     bytecode: bytecode_.unwrap_or_default(),
-                    datastore: datastore.unwrap_or_default(),
+                    datastore: datastore_,
                 },
             )?;
         }
@@ -210,23 +552,171 @@ pub(crate) fn execute_step(
             target_address,
             target_handler,
             execution_slot,
+            validity_end,
             gas,
+            gas_price,
             coins,
             data,
-        } => exec_context.push_async_message(
-            execution_slot,
-            AsyncMessage {
-                sender_address: emitter_address,
-                target_address,
+            trigger,
+        } => {
+            let trigger = match trigger {
+                Some(TriggerConfig {
+                    address,
+                    datastore_key,
+                }) => Some(AsyncMessageTrigger {
+                    address: exec_context.resolve(&address)?,
+                    datastore_key: datastore_key
+                        .map(|key| key.to_bytes(exec_context))
+                        .transpose()?,
+                }),
+                None => None,
+            };
+            let message = AsyncMessage {
+                sender_address: exec_context.resolve(&emitter_address)?,
+                target_address: exec_context.resolve(&target_address)?,
                 target_handler,
+                validity_start: execution_slot,
+                validity_end: validity_end.unwrap_or(Slot {
+                    period: u64::MAX,
+                    thread: u8::MAX,
+                }),
                 gas,
+                gas_price: gas_price.unwrap_or_default(),
                 coins,
-                data,
-            },
-        )?,
+                data: data.to_bytes(exec_context)?,
+                trigger,
+                ..Default::default()
+            };
+            // a sender that cannot pay fails the step, the message is not emitted
+            let json = match exec_context.debit_async_message(&message) {
+                Ok(fee) => {
+                    let json = object!(
+                        write_async_message: {
+                            sender_address: message.sender_address.clone(),
+                            debited_coins: message.coins,
+                            fee: fee,
+                        }
+                    );
+                    exec_context.push_async_message(message)?;
+                    json
+                }
+                Err(err) => {
+                    failure = Some(err.to_string());
+                    object!(
+                        write_async_message: {
+                            sender_address: message.sender_address,
+                            error: err.to_string(),
+                        }
+                    )
+                }
+            };
+            trace.push(json)?;
+        }
+        StepConfig::SignData {
+            keypair,
+            data,
+            variable,
+        } => {
+            let data = data.to_bytes(exec_context)?;
+            let (signature, public_key) = exec_context.sign(&keypair, &data)?;
+            exec_context.set_variable(&variable, &signature)?;
+            let json = object!(
+                sign_data: {
+                    keypair: keypair,
+                    public_key: public_key,
+                    data: data,
+                    signature: signature,
+                }
+            );
+            trace.push(json)?;
+        }
+        StepConfig::SnapshotState { name } => {
+            exec_context.snapshot_state(&name)?;
+            trace.push(object!(snapshot_state: { name: name }))?;
+        }
+        StepConfig::RestoreState { name } => {
+            exec_context.restore_state(&name)?;
+            trace.push(object!(restore_state: { name: name }))?;
+        }
+        StepConfig::AssertBalance { address, balance } => {
+            let address = exec_context.resolve(&address)?;
+            let result = assertion::check_balance(exec_context, &address, balance);
+            failure = push_assertion(&mut trace, "assert_balance", result)?;
+        }
+        StepConfig::AssertDatastoreEntry {
+            address,
+            key,
+            value,
+        } => {
+            let address = exec_context.resolve(&address)?;
+            let key = key.to_bytes(exec_context)?;
+            let value = value
+                .map(|value| value.to_bytes(exec_context))
+                .transpose()?;
+            let result =
+                assertion::check_datastore_entry(exec_context, &address, &key, value.as_deref());
+            failure = push_assertion(&mut trace, "assert_datastore_entry", result)?;
+        }
+        StepConfig::AssertEventEmitted {
+            start,
+            end,
+            sender_address,
+            data,
+        } => {
+            let sender_address = resolve_opt(exec_context, sender_address)?;
+            let result = assertion::check_event_emitted(
+                exec_context,
+                start,
+                end,
+                sender_address.as_deref(),
+                &data,
+            );
+            failure = push_assertion(&mut trace, "assert_event_emitted", result)?;
+        }
+        StepConfig::AssertAsyncMessagePending {
+            start,
+            end,
+            sender_address,
+            target_address,
+            target_handler,
+        } => {
+            let sender_address = resolve_opt(exec_context, sender_address)?;
+            let target_address = resolve_opt(exec_context, target_address)?;
+            let result = assertion::check_async_message_pending(
+                exec_context,
+                start,
+                end,
+                sender_address.as_deref(),
+                target_address.as_deref(),
+                target_handler.as_deref(),
+            );
+            failure = push_assertion(&mut trace, "assert_async_message_pending", result)?;
+        }
+        StepConfig::AssertRemainingGasBetween { min, max } => {
+            let result = assertion::check_remaining_gas_between(exec_context, min, max);
+            failure = push_assertion(&mut trace, "assert_remaining_gas_between", result)?;
+        }
+        StepConfig::AssertReturnValue {
+            raw,
+            return_type,
+            value,
+        } => {
+            let raw = raw.map(|raw| raw.to_bytes(exec_context)).transpose()?;
+            let result = assertion::check_return_value(
+                exec_context,
+                raw.as_deref(),
+                return_type.as_ref(),
+                value.as_ref(),
+            );
+            failure = push_assertion(&mut trace, "assert_return_value", result)?;
+        }
     }
 
     // save the ledger
     exec_context.save()?;
-    Ok(trace)
+    Ok(StepOutput {
+        trace,
+        failure,
+        gas_used,
+    })
 }
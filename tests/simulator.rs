@@ -0,0 +1,61 @@
+use anyhow::Result;
+use massa_sc_tester::{Entry, GasCosts, Simulator};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+const USER_ADDRESS: &str = "user_address";
+const SC_ADDRESS: &str = "smart_contract_address";
+const GAS: u64 = 100_000;
+
+/// Simulator with a funded user and the example smart contract, whose `main` and `receive`
+/// functions emit an event
+fn simulator(bytecode: &[u8]) -> Result<Simulator> {
+    let mut ledger = BTreeMap::new();
+    ledger.insert(
+        USER_ADDRESS.to_string(),
+        Entry {
+            balance: 1000,
+            ..Default::default()
+        },
+    );
+    ledger.insert(
+        SC_ADDRESS.to_string(),
+        Entry {
+            balance: 16,
+            bytecode: bytecode.to_vec(),
+            ..Default::default()
+        },
+    );
+    let gas_costs = GasCosts::new(
+        PathBuf::from("gas_costs/abi_gas_costs.json"),
+        PathBuf::from("gas_costs/wasm_gas_costs.json"),
+    )?;
+    Simulator::new(ledger, gas_costs, 42)
+}
+
+#[test]
+fn simulator_runs_calls_end_to_end() -> Result<()> {
+    let bytecode = fs::read("config/basic_sc.wasm")?;
+    let mut simulator = simulator(&bytecode)?;
+
+    simulator.execute(USER_ADDRESS, &bytecode, None, &[], GAS)?;
+    simulator.call(USER_ADDRESS, SC_ADDRESS, "receive", &[7, 8, 9], 32, GAS)?;
+    assert_eq!(simulator.balance(USER_ADDRESS)?, 968);
+    assert_eq!(simulator.balance(SC_ADDRESS)?, 48);
+    assert_eq!(simulator.events(None, None)?.len(), 2);
+
+    // a failed call leaves the state untouched, transferred coins included
+    assert!(simulator
+        .call(USER_ADDRESS, SC_ADDRESS, "missing", &[], 8, GAS)
+        .is_err());
+    assert_eq!(simulator.balance(USER_ADDRESS)?, 968);
+    assert_eq!(simulator.balance(SC_ADDRESS)?, 48);
+
+    // a read-only call returns its events without keeping them
+    let output = simulator.read_only_call(USER_ADDRESS, SC_ADDRESS, "receive", &[], 0, GAS)?;
+    assert_eq!(output.events.len(), 1);
+    assert_eq!(simulator.events(None, None)?.len(), 2);
+
+    simulator.advance_slots(2)?;
+    assert_eq!(simulator.slot().thread, 2);
+    Ok(())
+}